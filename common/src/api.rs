@@ -1,76 +1,166 @@
 use std::cell::RefCell;
 use std::fs;
-use std::path::Path;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use anki::card::{Card, CardId};
+use anki::collection::{Collection, CollectionBuilder};
+use anki::notes::{Note, NoteId};
+use anki::notetype::Notetype;
+use anki::ops::Op;
+use anki::prelude::I18n;
 use anki::scheduler::answering::CardAnswer;
 use anki::scheduler::states::SchedulingStates;
+use anki::services::{ConfigService, DeckConfigService, SchedulerService, StatsService};
 use anki::timestamp::{TimestampMillis, TimestampSecs};
-use anki::{collection::CollectionBuilder, prelude::I18n};
+use anki_proto::scheduler::bury_or_suspend_cards_request::Mode as BuryOrSuspendMode;
 
-use crate::{CardNode, DeckNode, DeckTree, SyncManager, SyncResult, SyncStatus, Translations};
+use crate::error::{Error, Result};
+use crate::occlusion::OcclusionNote;
+use crate::session_stats::{CardKind, SessionStats};
+use crate::stats::studied_in;
+use crate::utils::{HtmlSegment, RichBlock};
+use crate::{
+    CardAction, CardNode, CardPage, CustomStudyDefaults, CustomStudyMode, DeckNode, DeckOptions,
+    DeckTree, FilteredDeckAction, MainWindow, PageLayout, SessionSummary, Stats, SyncManager,
+    SyncResult, SyncState, SyncStatus, Translations,
+};
 
 use slint::ModelRc;
 
+/// Where the collection is, see [`LearnSession::col`].
+pub enum CollectionSlot {
+    Open(Collection),
+    /// A sync worker owns the collection until it hands it back.
+    Syncing,
+    /// The collection could not be reopened after a full sync, so there is
+    /// nothing left to study until the app is restarted.
+    Failed(Error),
+}
+
 pub struct LearnSession {
-    pub collection: Rc<RefCell<anki::collection::Collection>>,
+    pub collection: Arc<Mutex<CollectionSlot>>,
     pub current_card: RefCell<Option<i64>>,
+    /// Note of `current_card`, for the note actions of the study menu.
+    pub current_note: RefCell<Option<i64>>,
     pub states: RefCell<Option<SchedulingStates>>,
     pub start_time: RefCell<Option<Instant>>,
-    pub sync_manager: Rc<SyncManager>,
+    pub sync_manager: Arc<SyncManager>,
+    /// The worker of the last [`start_sync`], joined by [`wait_for_sync`].
+    pub sync_worker: RefCell<Option<JoinHandle<()>>>,
+    pub media_folder: PathBuf,
+    /// Set while the current card asks for a typed answer.
+    pub type_answer: RefCell<Option<TypeAnswer>>,
+    /// Answers since the deck was opened, see [`start_session`].
+    pub stats: RefCell<SessionStats>,
+}
+
+/// The answer side of a type-in card, which can only be rendered once the
+/// answer has been typed.
+pub struct TypeAnswer {
+    marker: String,
+    expected: String,
+    combining: bool,
+    answer_html: String,
+}
+
+impl TypeAnswer {
+    fn answer_html(&self, typed: &str) -> String {
+        let comparison = anki::typeanswer::compare_answer(&self.expected, typed, self.combining);
+        self.answer_html.replace(&self.marker, &comparison)
+    }
 }
 
-pub fn init_session(config: &crate::config::Config) -> Rc<LearnSession> {
-    let collection_path = &config.general.collection_path;
-    let language = &config.general.language;
+/// Only ever constructed around [`CollectionSlot::Open`], see
+/// [`LearnSession::col`].
+pub struct CollectionGuard<'a>(MutexGuard<'a, CollectionSlot>);
 
-    let col_file = format!("{}/collection.anki2", collection_path);
+impl Deref for CollectionGuard<'_> {
+    type Target = Collection;
 
-    if let Some(parent) = Path::new(&col_file).parent() {
-        if let Err(e) = fs::create_dir_all(parent) {
-            eprintln!("Failed to create collection directory: {:?}", e);
-            panic!(
-                "Cannot create collection directory at: {}",
-                parent.display()
-            );
+    fn deref(&self) -> &Collection {
+        match &*self.0 {
+            CollectionSlot::Open(col) => col,
+            _ => unreachable!(),
         }
     }
+}
 
-    let mut col = match CollectionBuilder::new(&col_file)
-        .set_tr(I18n::new(&[language.as_str()]))
-        .build()
-    {
-        Ok(col) => col,
-        Err(e) => {
-            eprintln!("Failed to open collection: {:?}", e);
-            panic!("Cannot continue without a valid collection.");
+impl DerefMut for CollectionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Collection {
+        match &mut *self.0 {
+            CollectionSlot::Open(col) => col,
+            _ => unreachable!(),
         }
-    };
+    }
+}
 
-    let search = "note:\"Image Occlusion Enhanced\"";
+impl LearnSession {
+    pub fn col(&self) -> Result<CollectionGuard<'_>> {
+        let guard = self.collection.lock().unwrap();
+        match &*guard {
+            CollectionSlot::Open(_) => Ok(CollectionGuard(guard)),
+            CollectionSlot::Syncing => Err(Error::CollectionBusy),
+            CollectionSlot::Failed(e) => Err(e.clone()),
+        }
+    }
 
-    if let Ok(card_ids) = col.search_cards(search, anki::search::SortMode::NoOrder) {
-        let _ = col.bury_or_suspend_cards(
-            &card_ids,
-            anki_proto::scheduler::bury_or_suspend_cards_request::Mode::Suspend,
-        );
+    pub fn is_syncing(&self) -> bool {
+        matches!(*self.collection.lock().unwrap(), CollectionSlot::Syncing)
     }
+}
+
+fn open_collection(
+    config: &crate::config::Config,
+    sync_manager: &SyncManager,
+) -> Result<Collection> {
+    let col_file = format!("{}/collection.anki2", config.general.collection_path);
+
+    if let Some(parent) = Path::new(&col_file).parent() {
+        fs::create_dir_all(parent).map_err(|e| {
+            Error::Collection(format!(
+                "Cannot create collection directory at {}: {}",
+                parent.display(),
+                e
+            ))
+        })?;
+    }
+
+    CollectionBuilder::new(&col_file)
+        .set_tr(I18n::new(&[config.general.language.as_str()]))
+        .set_shared_progress_state(sync_manager.progress_state())
+        .build()
+        .map_err(|e| Error::Collection(format!("Cannot open {}: {}", col_file, e)))
+}
 
-    Rc::new(LearnSession {
-        collection: Rc::new(RefCell::new(col)),
+pub fn init_session(config: &crate::config::Config) -> Result<Rc<LearnSession>> {
+    let sync_manager = Arc::new(SyncManager::new().with_endpoint(config.ankiweb.endpoint.clone()));
+
+    let col = open_collection(config, &sync_manager)?;
+
+    Ok(Rc::new(LearnSession {
+        collection: Arc::new(Mutex::new(CollectionSlot::Open(col))),
         current_card: RefCell::new(None),
+        current_note: RefCell::new(None),
         states: RefCell::new(None),
         start_time: RefCell::new(None),
-        sync_manager: Rc::new(SyncManager::new()),
-    })
+        sync_manager,
+        sync_worker: RefCell::new(None),
+        media_folder: crate::media::media_folder(&config.general.collection_path),
+        type_answer: RefCell::new(None),
+        stats: RefCell::default(),
+    }))
 }
 
-pub fn init_translations(session: &LearnSession) -> Translations {
-    let col_borrow = session.collection.borrow();
+pub fn init_translations(session: &LearnSession) -> Result<Translations> {
+    let col_borrow = session.col()?;
     let i181 = col_borrow.tr();
 
-    Translations {
+    Ok(Translations {
         show_answer: i181.studying_show_answer().as_ref().into(),
         again: i181.studying_again().as_ref().into(),
         hard: i181.studying_hard().as_ref().into(),
@@ -81,28 +171,525 @@ pub fn init_translations(session: &LearnSession) -> Translations {
             full.as_ref().replace("0", "").into()
         },
         no_cards_due: i181.studying_no_cards_are_due_yet().as_ref().into(),
-    }
+        upload_to_ankiweb: i181.sync_upload_to_ankiweb().as_ref().into(),
+        download_from_ankiweb: i181.sync_download_from_ankiweb().as_ref().into(),
+        username: i181.sync_ankiweb_id_label().as_ref().into(),
+        password: i181.sync_password_label().as_ref().into(),
+        log_in: i181.sync_log_in_button().as_ref().into(),
+        cancel: i181.actions_cancel().as_ref().into(),
+        bury_card: i181.studying_bury_card().as_ref().into(),
+        bury_note: i181.studying_bury_note().as_ref().into(),
+        suspend_card: i181.actions_suspend_card().as_ref().into(),
+        suspend_note: i181.studying_suspend_note().as_ref().into(),
+        forget_card: i181.actions_forget_card().as_ref().into(),
+        mark_note: i181.studying_mark_note().as_ref().into(),
+        new_cards: i181.statistics_counts_new_cards().as_ref().into(),
+        learning_cards: i181.statistics_counts_learning_cards().as_ref().into(),
+        review_cards: i181.scheduling_reviews().as_ref().into(),
+        continue_studying: i181.studying_continue().as_ref().into(),
+        finish: i181.studying_finish().as_ref().into(),
+        close: i181.actions_close().as_ref().into(),
+        stats_today: i181.statistics_today_title().as_ref().into(),
+        stats_future_due: i181.statistics_future_due_title().as_ref().into(),
+        stats_calendar: i181.statistics_calendar_title().as_ref().into(),
+        stats_card_counts: i181.statistics_counts_title().as_ref().into(),
+        stats_true_retention: i181.statistics_true_retention_title().as_ref().into(),
+        stats_retrievability: i181.statistics_retrievability_title().as_ref().into(),
+        stats_deck: i181.statistics_range_deck().as_ref().into(),
+        stats_collection: i181.statistics_range_collection().as_ref().into(),
+        stats: i181.qt_misc_stats().as_ref().into(),
+        options: i181.actions_options().as_ref().into(),
+        save: i181.actions_save().as_ref().into(),
+        new_per_day: i181.scheduling_new_cardsday().as_ref().into(),
+        reviews_per_day: i181.scheduling_maximum_reviewsday().as_ref().into(),
+        today_only: i181.deck_config_today_only().as_ref().into(),
+        learning_steps: i181.deck_config_learning_steps().as_ref().into(),
+        relearning_steps: i181.deck_config_relearning_steps().as_ref().into(),
+        graduating_interval: i181.scheduling_graduating_interval().as_ref().into(),
+        easy_interval: i181.scheduling_easy_interval().as_ref().into(),
+        maximum_interval: i181.scheduling_maximum_interval().as_ref().into(),
+        desired_retention: i181.deck_config_desired_retention().as_ref().into(),
+        bury_new: i181.deck_config_bury_new_siblings().as_ref().into(),
+        bury_reviews: i181.deck_config_bury_review_siblings().as_ref().into(),
+        bury_interday_learning: i181
+            .deck_config_bury_interday_learning_siblings()
+            .as_ref()
+            .into(),
+        custom_study: i181.actions_custom_study().as_ref().into(),
+        custom_study_modes: Rc::new(slint::VecModel::from(
+            [
+                i181.custom_study_increase_todays_new_card_limit(),
+                i181.custom_study_increase_todays_review_card_limit(),
+                i181.custom_study_review_forgotten_cards(),
+                i181.custom_study_review_ahead(),
+                i181.custom_study_preview_new_cards(),
+            ]
+            .iter()
+            .map(|name| name.as_ref().into())
+            .collect::<Vec<slint::SharedString>>(),
+        ))
+        .into(),
+        custom_study_values: Rc::new(slint::VecModel::from(
+            [
+                i181.custom_study_increase_todays_new_card_limit_by(),
+                i181.custom_study_increase_todays_review_limit_by(),
+                i181.custom_study_review_cards_forgotten_in_last(),
+                i181.custom_study_review_ahead_by(),
+                i181.custom_study_preview_new_cards_added_in_the(),
+            ]
+            .iter()
+            .map(|label| label.as_ref().into())
+            .collect::<Vec<slint::SharedString>>(),
+        ))
+        .into(),
+        cards: i181.custom_study_cards().as_ref().into(),
+        days: i181.scheduling_days().as_ref().into(),
+        rebuild: i181.actions_rebuild().as_ref().into(),
+        empty: i181.studying_empty().as_ref().into(),
+        delete: i181.actions_delete().as_ref().into(),
+        flags: Rc::new(slint::VecModel::from(
+            [
+                i181.actions_flag_red(),
+                i181.actions_flag_orange(),
+                i181.actions_flag_green(),
+                i181.actions_flag_blue(),
+                i181.actions_flag_pink(),
+                i181.actions_flag_turquoise(),
+                i181.actions_flag_purple(),
+            ]
+            .iter()
+            .map(|name| name.as_ref().into())
+            .collect::<Vec<slint::SharedString>>(),
+        ))
+        .into(),
+    })
 }
 
-pub fn update_deck_tree(session: &LearnSession) -> DeckTree {
+pub fn update_deck_tree(session: &LearnSession) -> Result<DeckTree> {
     let deck_tree = session
-        .collection
-        .borrow_mut()
+        .col()?
         .deck_tree(Some(TimestampSecs::now()))
-        .unwrap();
+        .map_err(Error::collection)?;
 
     let deck_nodes = crate::utils::flatten_tree(&deck_tree);
 
-    DeckTree {
+    Ok(DeckTree {
         due_total: deck_tree.review_count as i32,
         deck_nodes: deck_nodes.into(),
+    })
+}
+
+/// Review statistics of a deck and its subdecks, or of the whole collection.
+pub fn deck_stats(session: &LearnSession, deck: DeckNode, whole_collection: bool) -> Result<Stats> {
+    let mut col = session.col()?;
+    let (search, scope) = if whole_collection {
+        ("", col.tr().statistics_range_collection().into())
+    } else {
+        select_deck(&mut col, &deck)?;
+        let deck = col.get_current_deck().map_err(Error::collection)?;
+        ("deck:current", deck.human_name())
+    };
+
+    let graphs = col
+        .graphs(anki_proto::stats::GraphsRequest {
+            search: search.into(),
+            days: crate::stats::HEATMAP_WEEKS * 7,
+        })
+        .map_err(Error::collection)?;
+
+    Ok(crate::stats::stats(&graphs, col.tr(), scope))
+}
+
+/// The options of the preset `deck` uses, and its "today only" limits.
+pub fn deck_options(session: &LearnSession, deck: DeckNode) -> Result<DeckOptions> {
+    let mut col = session.col()?;
+    let configs = col
+        .get_deck_configs_for_update(anki::decks::DeckId(deck.id))
+        .map_err(Error::collection)?;
+
+    crate::deck_options::from_configs(&configs, col.tr())
+        .ok_or_else(|| Error::Collection(format!("No options preset for {}", deck.name)))
+}
+
+/// Saves edited options to the preset `deck` uses, which changes them for
+/// every deck sharing it, and the deck's "today only" limits.
+pub fn save_deck_options(
+    session: &LearnSession,
+    deck: DeckNode,
+    options: DeckOptions,
+) -> Result<()> {
+    let mut col = session.col()?;
+    let configs = col
+        .get_deck_configs_for_update(anki::decks::DeckId(deck.id))
+        .map_err(Error::collection)?;
+    let current = configs.current_deck.clone().unwrap_or_default();
+
+    let mut preset = configs
+        .all_config
+        .iter()
+        .filter_map(|preset| preset.config.clone())
+        .find(|config| config.id == current.config_id)
+        .ok_or_else(|| Error::Collection(format!("No options preset for {}", deck.name)))?;
+    crate::deck_options::apply(&options, preset.config.get_or_insert_with(Default::default));
+
+    let mut limits = current.limits.unwrap_or_default();
+    crate::deck_options::apply_limits(&options, &mut limits);
+
+    // The deck keeps the last preset given, and the collection wide settings
+    // have to be passed back unchanged.
+    DeckConfigService::update_deck_configs(
+        &mut *col,
+        anki_proto::deck_config::UpdateDeckConfigsRequest {
+            target_deck_id: deck.id,
+            configs: vec![preset],
+            limits: Some(limits),
+            card_state_customizer: configs.card_state_customizer,
+            new_cards_ignore_review_limit: configs.new_cards_ignore_review_limit,
+            apply_all_parent_limits: configs.apply_all_parent_limits,
+            fsrs: configs.fsrs,
+            ..Default::default()
+        },
+    )
+    .map_err(Error::collection)?;
+    Ok(())
+}
+
+/// How far today's limits of `deck` are raised by default for custom study,
+/// and how many more cards there are.
+pub fn custom_study_defaults(
+    session: &LearnSession,
+    deck: DeckNode,
+) -> Result<CustomStudyDefaults> {
+    let defaults = SchedulerService::custom_study_defaults(
+        &mut *session.col()?,
+        anki_proto::scheduler::CustomStudyDefaultsRequest { deck_id: deck.id },
+    )
+    .map_err(Error::scheduling)?;
+
+    Ok(CustomStudyDefaults {
+        new_limit: defaults.extend_new as i32,
+        new_available: defaults.available_new as i32,
+        review_limit: defaults.extend_review as i32,
+        review_available: defaults.available_review as i32,
+    })
+}
+
+/// Anki's custom study. Raising a limit applies to `deck` today, the other
+/// modes fill the "Custom Study Session" filtered deck with cards of `deck`,
+/// replacing the session before it. `value` is in cards or days.
+pub fn custom_study(
+    session: &LearnSession,
+    deck: DeckNode,
+    mode: CustomStudyMode,
+    value: i32,
+) -> Result<()> {
+    use anki_proto::scheduler::custom_study_request::Value;
+
+    let value = match mode {
+        CustomStudyMode::NewLimit => Value::NewLimitDelta(value),
+        CustomStudyMode::ReviewLimit => Value::ReviewLimitDelta(value),
+        CustomStudyMode::Forgotten => Value::ForgotDays(value.max(1) as u32),
+        CustomStudyMode::ReviewAhead => Value::ReviewAheadDays(value.max(1)),
+        CustomStudyMode::PreviewNew => Value::PreviewDays(value.max(1) as u32),
+    };
+
+    SchedulerService::custom_study(
+        &mut *session.col()?,
+        anki_proto::scheduler::CustomStudyRequest {
+            deck_id: deck.id,
+            value: Some(value),
+        },
+    )
+    .map_err(Error::scheduling)?;
+    Ok(())
+}
+
+/// Rebuilds, empties or deletes a filtered deck. Emptying or deleting one
+/// returns its cards to their home decks.
+pub fn filtered_deck_action(
+    session: &LearnSession,
+    deck: DeckNode,
+    action: FilteredDeckAction,
+) -> Result<()> {
+    if !deck.filtered {
+        return Err(Error::Collection(format!(
+            "{} is not a filtered deck",
+            deck.name
+        )));
+    }
+
+    let mut col = session.col()?;
+    let deck_id = anki::decks::DeckId(deck.id);
+    match action {
+        FilteredDeckAction::Rebuild => col.rebuild_filtered_deck(deck_id).map(drop),
+        FilteredDeckAction::Empty => col.empty_filtered_deck(deck_id).map(drop),
+        FilteredDeckAction::Delete => col.remove_decks_and_child_cards(&[deck_id]).map(drop),
     }
+    .map_err(Error::scheduling)?;
+    Ok(())
 }
 
-pub fn next_card(session: &LearnSession, deck: DeckNode, chars_per_page: i32) -> CardNode {
-    let mut col_borrow = session.collection.borrow_mut();
-    let _ = col_borrow.set_current_deck(anki::decks::DeckId(deck.id));
-    let queued_cards = col_borrow.get_queued_cards(1, false).unwrap();
+fn lines_page(lines: Vec<crate::TextLine>) -> CardPage {
+    CardPage {
+        lines: Rc::new(slint::VecModel::from(lines)).into(),
+        ..Default::default()
+    }
+}
+
+/// Splits rendered card HTML into pages, giving every image a page of its own
+/// in between the text pages around it.
+fn card_pages(
+    html: &str,
+    remove_brackets: bool,
+    layout: &PageLayout,
+    media_folder: &Path,
+) -> Vec<CardPage> {
+    let mut pages = html_pages(html, remove_brackets, layout, media_folder);
+
+    if pages.is_empty() {
+        pages.push(lines_page(Vec::new()));
+    }
+
+    pages
+}
+
+fn text_pages(blocks: &[RichBlock], layout: &PageLayout) -> Vec<CardPage> {
+    crate::utils::paginate_blocks(blocks, layout)
+        .into_iter()
+        .map(lines_page)
+        .collect()
+}
+
+fn image_error_pages(src: &str, error: &Error, layout: &PageLayout) -> Vec<CardPage> {
+    text_pages(
+        &[RichBlock::plain(&format!("[{}: {}]", src, error))],
+        layout,
+    )
+}
+
+fn scale_factor(layout: &PageLayout) -> f32 {
+    if layout.scale_factor > 0.0 {
+        layout.scale_factor
+    } else {
+        1.0
+    }
+}
+
+/// The largest image a page can show, in physical pixels, so that the
+/// dithered bitmap isn't scaled again on screen.
+fn image_bounds(layout: &PageLayout) -> (u32, u32) {
+    let scale = scale_factor(layout);
+    (
+        (layout.width * scale).max(1.0) as u32,
+        (layout.height * scale).max(1.0) as u32,
+    )
+}
+
+fn image_page(image: slint::Image, layout: &PageLayout) -> CardPage {
+    let scale = scale_factor(layout);
+    let size = image.size();
+    CardPage {
+        image,
+        image_width: size.width as f32 / scale,
+        image_height: size.height as f32 / scale,
+        has_image: true,
+        ..Default::default()
+    }
+}
+
+/// Like [`card_pages`], but empty HTML gives no pages at all.
+fn html_pages(
+    html: &str,
+    remove_brackets: bool,
+    layout: &PageLayout,
+    media_folder: &Path,
+) -> Vec<CardPage> {
+    let html = crate::math::render_math(html);
+    let html = if remove_brackets {
+        crate::utils::remove_double_brackets(&html)
+    } else {
+        html
+    };
+    let mut pages = Vec::new();
+
+    for segment in crate::utils::split_images(&html) {
+        match segment {
+            HtmlSegment::Html(html) => {
+                pages.extend(text_pages(&crate::utils::html_to_blocks(html), layout))
+            }
+            HtmlSegment::Image(tag) => {
+                let (width, height) = image_bounds(layout);
+                let image = crate::media::resolve(media_folder, &tag.src)
+                    .map(|path| crate::media::load_image(&path, width, height));
+
+                match image {
+                    Some(Ok(image)) => pages.push(image_page(image, layout)),
+                    // LaTeX images are generated by the desktop app and may
+                    // not have been synced yet, their source is in `alt`.
+                    _ if tag.class.split_whitespace().any(|c| c == "latex") => {
+                        let tex = crate::math::tex_to_unicode(&tag.alt);
+                        let blocks: Vec<_> = tex.lines().map(RichBlock::plain).collect();
+                        pages.extend(text_pages(&blocks, layout))
+                    }
+                    Some(Err(e)) => pages.extend(image_error_pages(&tag.src, &e, layout)),
+                    // Remote images are not fetched.
+                    None => {}
+                }
+            }
+        }
+    }
+
+    pages
+}
+
+/// Image occlusion cards: the header, then the image with the masks drawn
+/// over it, then on the answer side the extra fields.
+fn occlusion_pages(
+    occlusion: &OcclusionNote,
+    ordinal: u16,
+    layout: &PageLayout,
+    media_folder: &Path,
+) -> (Vec<CardPage>, Vec<CardPage>) {
+    let (max_width, max_height) = image_bounds(layout);
+    let masked_image = |reveal: bool| {
+        let image = crate::media::resolve(media_folder, &occlusion.image)
+            .ok_or_else(|| Error::Render("not in the media folder".into()))
+            .and_then(|path| {
+                crate::media::load_image_with_overlay(
+                    &path,
+                    max_width,
+                    max_height,
+                    |width, height| occlusion.overlay(media_folder, ordinal, reveal, width, height),
+                )
+            });
+
+        match image {
+            Ok(image) => vec![image_page(image, layout)],
+            Err(e) => image_error_pages(&occlusion.image, &e, layout),
+        }
+    };
+
+    let mut question = html_pages(&occlusion.header, false, layout, media_folder);
+    question.extend(masked_image(false));
+
+    let mut answer = html_pages(&occlusion.header, false, layout, media_folder);
+    answer.extend(masked_image(true));
+    answer.extend(html_pages(&occlusion.extra, false, layout, media_folder));
+
+    (question, answer)
+}
+
+/// Selects `deck` for the scheduler. That is an undoable change, so it is
+/// only made when another deck is current.
+fn select_deck(col: &mut Collection, deck: &DeckNode) -> Result<()> {
+    let id = anki::decks::DeckId(deck.id);
+    if col.get_current_deck().map_err(Error::collection)?.id != id {
+        col.set_current_deck(id).map_err(Error::collection)?;
+    }
+    Ok(())
+}
+
+/// The last change if the study screen made it: an answer or one of the
+/// card actions. Anything else is left for the desktop to undo.
+fn review_undo_op(col: &Collection) -> Option<Op> {
+    col.undo_status().undo.filter(|op| {
+        matches!(
+            op,
+            Op::AnswerCard
+                | Op::Bury
+                | Op::Suspend
+                | Op::ScheduleAsNew
+                | Op::SetFlag
+                | Op::UpdateTag
+                | Op::RemoveTag
+        )
+    })
+}
+
+/// Label of the action `undo` would take back, like "Undo Answer Card",
+/// empty if there is nothing to undo.
+fn undo_label(col: &Collection) -> String {
+    review_undo_op(col)
+        .map(|op| col.tr().undo_undo_action(op.describe(col.tr())).into())
+        .unwrap_or_default()
+}
+
+fn session_summary(col: &Collection, stats: &SessionStats) -> SessionSummary {
+    let time = stats.time_taken();
+    let headline = if stats.answered() == 0 {
+        col.tr().studying_no_cards_are_due_yet().into()
+    } else {
+        studied_in(col.tr(), stats.answered(), time)
+    };
+
+    SessionSummary {
+        answered: stats.answered() as i32,
+        ratings: Rc::new(slint::VecModel::from(
+            (0..4)
+                .map(|rating| stats.rating_count(rating) as i32)
+                .collect::<Vec<_>>(),
+        ))
+        .into(),
+        new: stats.kind_count(CardKind::New) as i32,
+        learn: stats.kind_count(CardKind::Learning) as i32,
+        review: stats.kind_count(CardKind::Review) as i32,
+        time: format!("{}:{:02}", time.as_secs() / 60, time.as_secs() % 60).into(),
+        headline: headline.into(),
+    }
+}
+
+/// The end of the queue, the study screen shows the session summary instead.
+fn no_more_cards(deck: DeckNode, undo: String, summary: SessionSummary) -> CardNode {
+    CardNode {
+        id: -1,
+        deck,
+        question: ModelRc::new(slint::VecModel::default()),
+        answer: ModelRc::new(slint::VecModel::default()),
+        durations: ModelRc::new(slint::VecModel::default()),
+        type_answer: false,
+        undo: undo.into(),
+        flag: 0,
+        marked: false,
+        summary,
+        time_up: "".into(),
+    }
+}
+
+/// Anki's time limit in seconds from the preferences, 0 when there is none.
+fn time_limit(col: &mut Collection) -> Duration {
+    let seconds = col
+        .get_config_json(anki_proto::generic::String {
+            val: "timeLim".into(),
+        })
+        .ok()
+        .and_then(|json| {
+            String::from_utf8(json.json)
+                .ok()?
+                .trim()
+                .parse::<f64>()
+                .ok()
+        })
+        .unwrap_or(0.0);
+    Duration::from_secs_f64(seconds.max(0.0))
+}
+
+/// Opens a deck for studying, with fresh session stats.
+pub fn start_session(
+    session: &LearnSession,
+    deck: DeckNode,
+    layout: PageLayout,
+) -> Result<CardNode> {
+    let limit = time_limit(&mut session.col()?);
+    *session.stats.borrow_mut() = SessionStats::new(limit);
+    next_card(session, deck, layout)
+}
+
+pub fn next_card(session: &LearnSession, deck: DeckNode, layout: PageLayout) -> Result<CardNode> {
+    let mut col_borrow = session.col()?;
+    select_deck(&mut col_borrow, &deck)?;
+    let queued_cards = col_borrow
+        .get_queued_cards(1, false)
+        .map_err(Error::scheduling)?;
     let queued_card = queued_cards.cards.first();
 
     if let Some(card) = queued_card {
@@ -115,29 +702,59 @@ pub fn next_card(session: &LearnSession, deck: DeckNode, chars_per_page: i32) ->
 
         let rendered = col_borrow
             .render_existing_card(card.card.id(), false, false)
-            .unwrap();
+            .map_err(Error::render)?;
 
-        let mut answer = anki::text::strip_html(rendered.answer().as_ref()).into_owned();
+        let answer = rendered.answer().into_owned();
+        let undo = undo_label(&col_borrow);
+        let summary = session_summary(&col_borrow, &session.stats.borrow());
 
-        // Handle special case for type in cards
-        if answer.contains("[[type:") {
-            let note_opt = col_borrow.storage.get_note(card.card.note_id()).unwrap();
+        let note = card_note(&mut col_borrow, &card.card)?;
+        let marked = note.as_ref().is_some_and(|(note, _)| {
+            note.tags
+                .iter()
+                .any(|tag| tag.eq_ignore_ascii_case(MARKED_TAG))
+        });
+        let flag = anki_proto::cards::Card::from(card.card.clone()).flags as i32;
+        let occlusion = note
+            .as_ref()
+            .and_then(|(note, notetype)| OcclusionNote::from_note(notetype, note));
 
-            if let Some(note) = note_opt {
-                let fields = note.fields();
-                let notetype_id = note.notetype_id;
-                let notetype = col_borrow.get_notetype(notetype_id).unwrap();
-
-                if let Some(notetype) = notetype {
-                    for (index, field) in notetype.fields.iter().enumerate() {
-                        if field.name == "Back" {
-                            answer = (&fields[index]).to_owned();
-                        }
-                    }
-                }
+        let type_answer = match (crate::type_answer::find(&rendered.question()), &note) {
+            (Some(field), Some((note, notetype))) => {
+                let cloze_ord = field.cloze.then(|| card.card.template_idx() + 1);
+                typed_field(note, notetype, &field.field).map(|html| TypeAnswer {
+                    expected: crate::type_answer::expected(&html, cloze_ord),
+                    marker: field.marker,
+                    combining: field.combining,
+                    answer_html: answer.clone(),
+                })
             }
-        }
+            _ => None,
+        };
+        // Until something has been typed the answer shows the expected text.
+        let answer = type_answer
+            .as_ref()
+            .map_or(answer, |type_answer| type_answer.answer_html(""));
+
         drop(col_borrow);
+
+        let answer_layout = PageLayout {
+            reveal_clozes: true,
+            ..layout.clone()
+        };
+        let (question, answer) = match &occlusion {
+            Some(occlusion) => occlusion_pages(
+                occlusion,
+                card.card.template_idx() + 1,
+                &layout,
+                &session.media_folder,
+            ),
+            None => (
+                card_pages(&rendered.question(), true, &layout, &session.media_folder),
+                card_pages(&answer, true, &answer_layout, &session.media_folder),
+            ),
+        };
+
         let updated_deck = DeckNode {
             new: queued_cards.new_count as i32,
             learn: queued_cards.learning_count as i32,
@@ -147,19 +764,14 @@ pub fn next_card(session: &LearnSession, deck: DeckNode, chars_per_page: i32) ->
         let card_node = CardNode {
             id: card.card.id().0,
             deck: updated_deck,
-            question: Rc::new(slint::VecModel::from(
-                crate::utils::strip_html_remove_brackets_and_paginate(
-                    rendered.question().as_ref(),
-                    true,
-                    chars_per_page as usize,
-                ),
-            ))
-            .into(),
-            answer: Rc::new(slint::VecModel::from(crate::utils::paginate_text(
-                &answer,
-                chars_per_page as usize,
-            )))
-            .into(),
+            question: Rc::new(slint::VecModel::from(question)).into(),
+            answer: Rc::new(slint::VecModel::from(answer)).into(),
+            type_answer: type_answer.is_some(),
+            undo: undo.into(),
+            flag,
+            marked,
+            summary,
+            time_up: "".into(),
             durations: Rc::new(slint::VecModel::from(
                 durations
                     .into_iter()
@@ -170,43 +782,95 @@ pub fn next_card(session: &LearnSession, deck: DeckNode, chars_per_page: i32) ->
         };
 
         *session.current_card.borrow_mut() = card_node.id.into();
-        card_node
+        *session.current_note.borrow_mut() = Some(card.card.note_id().0);
+        *session.type_answer.borrow_mut() = type_answer;
+        Ok(card_node)
     } else {
-        CardNode {
-            id: -1,
-            deck,
-            question: ModelRc::new(slint::VecModel::from(vec!["No more cards due!".into()])),
-            answer: ModelRc::new(slint::VecModel::default()),
-            durations: ModelRc::new(slint::VecModel::default()),
-        }
+        *session.current_card.borrow_mut() = None;
+        *session.current_note.borrow_mut() = None;
+        *session.type_answer.borrow_mut() = None;
+        let summary = session_summary(&col_borrow, &session.stats.borrow());
+        Ok(no_more_cards(deck, undo_label(&col_borrow), summary))
     }
 }
 
+fn card_note(col: &mut Collection, card: &Card) -> Result<Option<(Note, Arc<Notetype>)>> {
+    let Some(note) = col
+        .storage
+        .get_note(card.note_id())
+        .map_err(Error::collection)?
+    else {
+        return Ok(None);
+    };
+    let Some(notetype) = col
+        .get_notetype(note.notetype_id)
+        .map_err(Error::collection)?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some((note, notetype)))
+}
+
+/// The field content a `[[type:...]]` marker refers to, `None` if the note
+/// has no such field.
+fn typed_field(note: &Note, notetype: &Notetype, field: &str) -> Option<String> {
+    notetype
+        .fields
+        .iter()
+        .position(|f| f.name == field)
+        .and_then(|index| note.fields().get(index).cloned())
+}
+
+/// Renders the answer side of a type-in card with a character diff of the
+/// typed answer against the expected one.
+pub fn check_answer(
+    session: &LearnSession,
+    typed: &str,
+    layout: PageLayout,
+) -> Result<ModelRc<CardPage>> {
+    let type_answer = session.type_answer.borrow();
+    let Some(type_answer) = type_answer.as_ref() else {
+        return Err(Error::Render(
+            "The current card has no answer to type".into(),
+        ));
+    };
+
+    let layout = PageLayout {
+        reveal_clozes: true,
+        ..layout
+    };
+    Ok(Rc::new(slint::VecModel::from(card_pages(
+        &type_answer.answer_html(typed),
+        true,
+        &layout,
+        &session.media_folder,
+    )))
+    .into())
+}
+
 pub fn rate_card(
     session: &LearnSession,
     rating: i32,
     deck: DeckNode,
-    chars_per_page: i32,
-) -> CardNode {
+    layout: PageLayout,
+) -> Result<CardNode> {
     let card_id = session.current_card.borrow().unwrap_or(-1);
     let states = session.states.borrow().clone();
 
     if card_id == -1 || states.is_none() {
-        return CardNode {
-            id: -1,
-            deck,
-            question: ModelRc::new(slint::VecModel::from(vec!["No more cards due!".into()])),
-            answer: ModelRc::new(slint::VecModel::default()),
-            durations: ModelRc::new(slint::VecModel::default()),
-        };
+        let col = session.col()?;
+        let summary = session_summary(&col, &session.stats.borrow());
+        return Ok(no_more_cards(deck, undo_label(&col), summary));
     }
 
     let states = states.unwrap();
+    let kind = CardKind::of(&states.current);
     let elapsed = session
         .start_time
         .borrow()
-        .map(|t| t.elapsed().as_millis() as u32)
-        .unwrap_or(0);
+        .map(|t| t.elapsed())
+        .unwrap_or_default();
 
     let (new_state, rating_enum) = match rating {
         0 => (
@@ -226,13 +890,7 @@ pub fn rate_card(
             anki::scheduler::answering::Rating::Easy,
         ),
         _ => {
-            return CardNode {
-                id: -1,
-                deck,
-                question: ModelRc::new(slint::VecModel::from(vec!["No more cards due!".into()])),
-                answer: ModelRc::new(slint::VecModel::default()),
-                durations: ModelRc::new(slint::VecModel::default()),
-            };
+            return Err(Error::Scheduling(format!("Unknown rating {}", rating)));
         }
     };
 
@@ -242,57 +900,485 @@ pub fn rate_card(
         new_state,
         rating: rating_enum,
         answered_at: TimestampMillis::now(),
-        milliseconds_taken: elapsed,
+        milliseconds_taken: elapsed.as_millis() as u32,
         custom_data: None,
         from_queue: true,
     };
 
-    let _ = session.collection.borrow_mut().answer_card(&mut answer);
+    session
+        .col()?
+        .answer_card(&mut answer)
+        .map_err(Error::scheduling)?;
+    session
+        .stats
+        .borrow_mut()
+        .record(rating as usize, kind, elapsed);
 
-    next_card(session, deck, chars_per_page)
+    let mut card = next_card(session, deck, layout)?;
+    // Like Anki, the time limit is only checked between cards.
+    let timebox = session.stats.borrow_mut().take_timebox();
+    if let Some((time, cards)) = timebox {
+        card.time_up = studied_in(session.col()?.tr(), cards, time).into();
+    }
+    Ok(card)
 }
 
-pub fn sync_ankiweb(session: &LearnSession, config: &crate::config::Config) -> SyncResult {
-    let rt = tokio::runtime::Runtime::new().unwrap();
+/// Tag Anki's "Mark Note" adds.
+const MARKED_TAG: &str = "marked";
 
-    let ankiweb_config = &config.ankiweb;
+fn current_ids(session: &LearnSession) -> Result<(CardId, NoteId)> {
+    match (
+        *session.current_card.borrow(),
+        *session.current_note.borrow(),
+    ) {
+        (Some(card), Some(note)) => Ok((CardId(card), NoteId(note))),
+        _ => Err(Error::Scheduling("No card is being studied".into())),
+    }
+}
+
+/// Buries, suspends or resets the current card or its note and moves on to
+/// the next card.
+pub fn card_action(
+    session: &LearnSession,
+    action: CardAction,
+    deck: DeckNode,
+    layout: PageLayout,
+) -> Result<CardNode> {
+    let (card_id, note_id) = current_ids(session)?;
+    {
+        let mut col = session.col()?;
+        let note_cards = |col: &mut Collection| {
+            col.search_cards(
+                format!("nid:{}", note_id.0).as_str(),
+                anki::search::SortMode::NoOrder,
+            )
+            .map_err(Error::collection)
+        };
+
+        match action {
+            CardAction::BuryCard => col
+                .bury_or_suspend_cards(&[card_id], BuryOrSuspendMode::BuryUser)
+                .map(drop),
+            CardAction::BuryNote => {
+                let cards = note_cards(&mut col)?;
+                col.bury_or_suspend_cards(&cards, BuryOrSuspendMode::BuryUser)
+                    .map(drop)
+            }
+            CardAction::SuspendCard => col
+                .bury_or_suspend_cards(&[card_id], BuryOrSuspendMode::Suspend)
+                .map(drop),
+            CardAction::SuspendNote => {
+                let cards = note_cards(&mut col)?;
+                col.bury_or_suspend_cards(&cards, BuryOrSuspendMode::Suspend)
+                    .map(drop)
+            }
+            // Back to a new card at its original position, keeping the review
+            // history, as Anki's Reset dialog does by default.
+            CardAction::Forget => col
+                .reschedule_cards_as_new(&[card_id], true, true, false, None)
+                .map(drop),
+        }
+        .map_err(Error::scheduling)?;
+    }
+
+    next_card(session, deck, layout)
+}
+
+/// Sets flag 1 to 7 on the current card, 0 removes it.
+pub fn set_flag(session: &LearnSession, flag: i32) -> Result<()> {
+    let (card_id, _) = current_ids(session)?;
+    session
+        .col()?
+        .set_card_flag(&[card_id], flag.clamp(0, 7) as u32)
+        .map_err(Error::collection)?;
+    Ok(())
+}
 
-    let hkey = if let Some(token) = &ankiweb_config.token {
-        token.clone()
+/// Adds or removes the "marked" tag on the current card's note.
+pub fn set_marked(session: &LearnSession, marked: bool) -> Result<()> {
+    let (_, note_id) = current_ids(session)?;
+    let mut col = session.col()?;
+    if marked {
+        col.add_tags_to_notes(&[note_id], MARKED_TAG)
     } else {
-        match rt.block_on(
-            session
-                .sync_manager
-                .login(&ankiweb_config.username, &ankiweb_config.password),
-        ) {
-            Ok(token) => {
-                let mut cfg = config.clone();
-                cfg.ankiweb.token = Some(token.clone());
-                let _ = cfg.save();
-                token
+        col.remove_tags_from_notes(&[note_id], MARKED_TAG)
+    }
+    .map_err(Error::collection)?;
+    Ok(())
+}
+
+/// Takes back the last change the study screen made, usually an answer.
+/// The scheduler puts an undone card back at the front of its queue, so it
+/// is shown again with the states it had before.
+pub fn undo(session: &LearnSession, deck: DeckNode, layout: PageLayout) -> Result<CardNode> {
+    {
+        let mut col = session.col()?;
+        if let Some(op) = review_undo_op(&col) {
+            col.undo().map_err(Error::collection)?;
+            if matches!(op, Op::AnswerCard) {
+                session.stats.borrow_mut().undo();
             }
-            Err(e) => {
-                return SyncResult {
-                    success: false,
-                    message: format!("Login failed: {}", e),
-                    server_message: None,
-                };
+        }
+    }
+    next_card(session, deck, layout)
+}
+
+/// A token to sync with. One from a new login may not have been saved.
+struct SyncAuth {
+    hkey: String,
+    save_error: Option<Error>,
+}
+
+impl SyncAuth {
+    fn new(hkey: String) -> Self {
+        SyncAuth {
+            hkey,
+            save_error: None,
+        }
+    }
+}
+
+/// Tells the user why the token wasn't saved, as they will have to log in
+/// again on the next launch.
+fn with_save_error(mut result: SyncResult, save_error: Option<Error>) -> SyncResult {
+    if let Some(e) = save_error {
+        result.message = format!("{} (token not saved: {})", result.message, e);
+    }
+    result
+}
+
+fn sync_hkey(
+    sync_manager: &SyncManager,
+    config: &crate::config::Config,
+    rt: &tokio::runtime::Runtime,
+) -> Result<SyncAuth, SyncResult> {
+    if let Some(hkey) = sync_manager.hkey() {
+        return Ok(SyncAuth::new(hkey));
+    }
+
+    if let Some(token) = config
+        .ankiweb
+        .token
+        .as_ref()
+        .filter(|token| !sync_manager.is_rejected(token))
+    {
+        return Ok(SyncAuth::new(token.clone()));
+    }
+
+    if config.ankiweb.username.is_empty() || config.ankiweb.password.is_empty() {
+        return Err(SyncResult::login_required(
+            "Log in to AnkiWeb to sync".into(),
+        ));
+    }
+
+    sync_login(sync_manager, config, rt)
+}
+
+/// Exchanges the credentials for a new token and persists it. The password
+/// itself is never written back to disk.
+fn sync_login(
+    sync_manager: &SyncManager,
+    config: &crate::config::Config,
+    rt: &tokio::runtime::Runtime,
+) -> Result<SyncAuth, SyncResult> {
+    let ankiweb_config = &config.ankiweb;
+
+    match rt.block_on(sync_manager.login(&ankiweb_config.username, &ankiweb_config.password)) {
+        Ok(token) => Ok(SyncAuth {
+            save_error: save_sync_config(sync_manager, config, Some(token.clone())),
+            hkey: token,
+        }),
+        Err(e) if SyncManager::is_auth_error(e.as_ref()) => Err(SyncResult::login_required(
+            crate::sync::CREDENTIALS_INVALID.into(),
+        )),
+        Err(e) => Err(SyncResult::failure(format!("Login failed: {}", e))),
+    }
+}
+
+/// Saves `token` for later launches, along with the server AnkiWeb may have
+/// moved the account to. Returns why the config couldn't be saved.
+fn save_sync_config(
+    sync_manager: &SyncManager,
+    config: &crate::config::Config,
+    token: Option<String>,
+) -> Option<Error> {
+    let mut cfg = config.clone();
+    cfg.ankiweb.token = token;
+    if let Some(endpoint) = sync_manager.endpoint() {
+        cfg.ankiweb.endpoint = Some(endpoint);
+    }
+    cfg.save().err()
+}
+
+fn sync_runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::sync)
+}
+
+fn run_normal_sync(
+    sync_manager: &SyncManager,
+    config: &crate::config::Config,
+    col: &mut Collection,
+    rt: &tokio::runtime::Runtime,
+) -> SyncResult {
+    let SyncAuth {
+        mut hkey,
+        mut save_error,
+    } = match sync_hkey(sync_manager, config, rt) {
+        Ok(auth) => auth,
+        Err(result) => return result,
+    };
+
+    let sync = |col: &mut Collection, hkey: &str| {
+        rt.block_on(async {
+            let sync_result = sync_manager.sync_collection(col, hkey).await?;
+            if !sync_result.success {
+                return Ok(sync_result);
+            }
+
+            // Media is synced after the collection, so that references added by
+            // the collection sync are picked up by the same run.
+            sync_manager
+                .sync_media(col, hkey, sync_result.server_media_usn)
+                .await?;
+
+            Ok::<_, Box<dyn std::error::Error>>(sync_result)
+        })
+    };
+
+    let result = match sync(col, &hkey) {
+        // The stored token expired or was revoked, so drop it and log in
+        // again once.
+        Err(e) if SyncManager::is_auth_error(e.as_ref()) => {
+            sync_manager.reject_hkey(&hkey);
+            let mut cfg = config.clone();
+            cfg.ankiweb.token = None;
+            save_error = save_sync_config(sync_manager, &cfg, None);
+            if cfg.ankiweb.password.is_empty() {
+                return with_save_error(
+                    SyncResult::login_required(crate::sync::CREDENTIALS_INVALID.into()),
+                    save_error,
+                );
+            }
+
+            match sync_login(sync_manager, &cfg, rt) {
+                Ok(auth) => {
+                    save_error = auth.save_error.or(save_error);
+                    hkey = auth.hkey;
+                    sync(col, &hkey)
+                }
+                Err(result) => return with_save_error(result, save_error),
             }
         }
+        result => result,
     };
 
-    let result = rt.block_on(async {
-        let mut col = session.collection.borrow_mut();
-        session.sync_manager.sync_collection(&mut col, &hkey).await
-    });
+    let result = match result {
+        Ok(sync_result) => sync_result,
+        Err(e) => SyncResult::failure(format!("Sync failed: {}", e)),
+    };
+    if result.new_endpoint.is_some() {
+        save_error = save_sync_config(sync_manager, config, Some(hkey)).or(save_error);
+    }
+    with_save_error(result, save_error)
+}
+
+/// Anki closes the collection as part of a full sync, so it is consumed here
+/// and reopened from disk afterwards, whether the transfer succeeded or not.
+fn run_full_sync(
+    sync_manager: &SyncManager,
+    config: &crate::config::Config,
+    col: Collection,
+    upload: bool,
+    rt: &tokio::runtime::Runtime,
+) -> (CollectionSlot, SyncResult) {
+    let SyncAuth { hkey, save_error } = match sync_hkey(sync_manager, config, rt) {
+        Ok(auth) => auth,
+        Err(result) => return (CollectionSlot::Open(col), result),
+    };
+
+    let result = rt.block_on(sync_manager.full_sync(col, &hkey, upload));
 
-    match result {
+    let mut col = match open_collection(config, sync_manager) {
+        Ok(col) => col,
+        Err(e) => {
+            let result = SyncResult::failure(format!("Failed to reopen collection: {}", e));
+            return (
+                CollectionSlot::Failed(e),
+                with_save_error(result, save_error),
+            );
+        }
+    };
+
+    let mut sync_result = match result {
         Ok(sync_result) => sync_result,
-        Err(e) => SyncResult {
-            success: false,
-            message: format!("Sync failed: {}", e),
-            server_message: None,
-        },
+        Err(e) => {
+            let result = SyncResult::failure(format!("Full sync failed: {}", e));
+            return (
+                CollectionSlot::Open(col),
+                with_save_error(result, save_error),
+            );
+        }
+    };
+
+    // A download may have brought in media references we don't have yet.
+    if let Err(e) = rt.block_on(sync_manager.sync_media(&mut col, &hkey, None)) {
+        sync_result.message = format!("{} (media sync failed: {})", sync_result.message, e);
+    }
+
+    (
+        CollectionSlot::Open(col),
+        with_save_error(sync_result, save_error),
+    )
+}
+
+/// Syncs on the calling thread. Only meant for places where the event loop
+/// is no longer running, such as on exit; use [`start_sync`] otherwise.
+pub fn sync_ankiweb(session: &LearnSession, config: &crate::config::Config) -> Result<SyncResult> {
+    let rt = sync_runtime()?;
+    let mut col = session.col()?;
+    Ok(run_normal_sync(
+        &session.sync_manager,
+        config,
+        &mut col,
+        &rt,
+    ))
+}
+
+#[derive(Debug, Clone)]
+pub enum SyncJob {
+    Normal,
+    /// Replaces one side with the other, in the direction chosen by the user.
+    Full {
+        upload: bool,
+    },
+    /// Logs in with credentials entered in the app, then syncs normally.
+    Login {
+        username: String,
+        password: String,
+    },
+}
+
+fn sync_state(status: &SyncStatus, is_syncing: bool) -> SyncState {
+    SyncState {
+        is_syncing,
+        status_message: status.message().into(),
+        has_error: matches!(status, SyncStatus::Error { .. }),
+        server_message: Default::default(),
+        full_sync_required: false,
+        login_required: false,
+    }
+}
+
+fn publish_sync_state(ui: &slint::Weak<MainWindow>, state: SyncState) {
+    let ui = ui.clone();
+    let _ = slint::invoke_from_event_loop(move || {
+        if let Some(ui) = ui.upgrade() {
+            ui.set_sync_state(state);
+        }
+    });
+}
+
+/// Hands the collection to a worker thread for the duration of the sync and
+/// mirrors status transitions into `MainWindow.sync_state`.
+pub fn start_sync(
+    session: &LearnSession,
+    config: &crate::config::Config,
+    ui: slint::Weak<MainWindow>,
+    job: SyncJob,
+) -> Result<()> {
+    let rt = sync_runtime()?;
+    let col = {
+        let mut slot = session.collection.lock().unwrap();
+        match std::mem::replace(&mut *slot, CollectionSlot::Syncing) {
+            CollectionSlot::Open(col) => col,
+            CollectionSlot::Syncing => return Err(Error::CollectionBusy),
+            CollectionSlot::Failed(e) => {
+                *slot = CollectionSlot::Failed(e.clone());
+                return Err(e);
+            }
+        }
+    };
+
+    // A full sync replaces the collection, so nothing about the current card
+    // can be trusted afterwards.
+    if let SyncJob::Full { .. } = job {
+        *session.current_card.borrow_mut() = None;
+        *session.states.borrow_mut() = None;
+    }
+
+    let slot = session.collection.clone();
+    let sync_manager = session.sync_manager.clone();
+    let config = config.clone();
+
+    if let Some(ui) = ui.upgrade() {
+        ui.set_sync_state(sync_state(&SyncStatus::CheckingStatus, true));
+    }
+    sync_manager.set_listener(Some(Box::new({
+        let ui = ui.clone();
+        move |status| publish_sync_state(&ui, sync_state(status, true))
+    })));
+
+    let worker = std::thread::spawn(move || {
+        let (col, result) = match job {
+            SyncJob::Normal => {
+                let mut col = col;
+                let result = run_normal_sync(&sync_manager, &config, &mut col, &rt);
+                (CollectionSlot::Open(col), result)
+            }
+            SyncJob::Full { upload } => run_full_sync(&sync_manager, &config, col, upload, &rt),
+            SyncJob::Login { username, password } => {
+                sync_manager.clear_hkey();
+                let mut config = config;
+                config.ankiweb.username = username;
+                config.ankiweb.password = password;
+                config.ankiweb.token = None;
+
+                let mut col = col;
+                let result = run_normal_sync(&sync_manager, &config, &mut col, &rt);
+                (CollectionSlot::Open(col), result)
+            }
+        };
+
+        sync_manager.set_listener(None);
+        *slot.lock().unwrap() = col;
+
+        let status = if result.success {
+            SyncStatus::Complete {
+                message: result.message.clone(),
+            }
+        } else {
+            SyncStatus::Error {
+                message: result.message.clone(),
+            }
+        };
+
+        let state = SyncState {
+            server_message: result.server_message.unwrap_or_default().into(),
+            full_sync_required: result.full_sync_required,
+            login_required: result.login_required,
+            ..sync_state(&status, false)
+        };
+
+        let _ = slint::invoke_from_event_loop(move || {
+            if let Some(ui) = ui.upgrade() {
+                ui.set_sync_state(state);
+                ui.invoke_update_deck_tree();
+            }
+        });
+    });
+    *session.sync_worker.borrow_mut() = Some(worker);
+
+    Ok(())
+}
+
+/// Blocks until a running sync worker has finished, whether it could hand
+/// the collection back or not.
+pub fn wait_for_sync(session: &LearnSession) {
+    if let Some(worker) = session.sync_worker.borrow_mut().take() {
+        let _ = worker.join();
     }
 }
 
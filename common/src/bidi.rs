@@ -0,0 +1,185 @@
+use std::ops::Range;
+
+use unicode_bidi::{BidiClass, BidiInfo, Level, bidi_class};
+
+use crate::TextRun;
+
+/// UI languages written right to left, the screens are mirrored for them.
+const RTL_LANGUAGES: &[&str] = &["ar", "ckb", "fa", "he", "ps", "sd", "ug", "ur", "yi"];
+
+pub fn is_rtl_language(code: &str) -> bool {
+    let language = code.split(['-', '_']).next().unwrap_or_default();
+    RTL_LANGUAGES
+        .iter()
+        .any(|rtl| language.eq_ignore_ascii_case(rtl))
+}
+
+/// Whether a paragraph runs right to left, decided by its first strong
+/// character as in UAX #9.
+pub fn is_rtl_paragraph(text: &str) -> bool {
+    BidiInfo::new(text, None)
+        .paragraphs
+        .first()
+        .is_some_and(|paragraph| paragraph.level.is_rtl())
+}
+
+/// Puts the runs of a wrapped line into display order, left to right.
+///
+/// Slint shapes each run on its own and guesses its direction from its
+/// script, so a run of Arabic or Hebrew is passed in logical order and comes
+/// out right to left. Runs are split where the embedding level changes, and
+/// right-to-left runs without any letters to guess from are reversed and
+/// mirrored here.
+pub fn reorder_line(runs: Vec<TextRun>, rtl: bool) -> Vec<TextRun> {
+    let text: String = runs.iter().map(|run| run.text.as_str()).collect();
+    if !rtl && !text.chars().any(is_strong_rtl) {
+        return runs;
+    }
+
+    let level = if rtl { Level::rtl() } else { Level::ltr() };
+    let bidi = BidiInfo::new(&text, Some(level));
+    let Some(paragraph) = bidi.paragraphs.first() else {
+        return runs;
+    };
+    let (levels, level_runs) = bidi.visual_runs(paragraph, paragraph.range.clone());
+
+    let mut bounds = Vec::with_capacity(runs.len());
+    let mut offset = 0;
+    for run in &runs {
+        bounds.push(offset..offset + run.text.len());
+        offset += run.text.len();
+    }
+
+    let mut visual = Vec::new();
+    for level_run in level_runs {
+        let reversed = levels[level_run.start].is_rtl();
+        let mut pieces: Vec<(&TextRun, &str)> = runs
+            .iter()
+            .zip(&bounds)
+            .filter_map(|(run, bounds)| Some((run, overlap(bounds, &level_run)?)))
+            .map(|(run, range)| (run, &run.text[range]))
+            .collect();
+        if reversed {
+            pieces.reverse();
+        }
+
+        for (run, text) in pieces {
+            let text = if reversed && !text.chars().any(is_strong_rtl) {
+                text.chars().rev().map(mirror).collect()
+            } else {
+                text.to_string()
+            };
+            visual.push(TextRun {
+                text: text.into(),
+                ..run.clone()
+            });
+        }
+    }
+
+    visual
+}
+
+/// `range` relative to the start of `bounds`, if they overlap.
+fn overlap(bounds: &Range<usize>, range: &Range<usize>) -> Option<Range<usize>> {
+    let start = bounds.start.max(range.start);
+    let end = bounds.end.min(range.end);
+    (start < end).then(|| start - bounds.start..end - bounds.start)
+}
+
+fn is_strong_rtl(c: char) -> bool {
+    matches!(bidi_class(c), BidiClass::R | BidiClass::AL)
+}
+
+/// Brackets point the other way in right-to-left text.
+fn mirror(c: char) -> char {
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        '‹' => '›',
+        '›' => '‹',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(texts: &[&str]) -> Vec<TextRun> {
+        texts
+            .iter()
+            .map(|text| TextRun {
+                text: (*text).into(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paragraph_direction() {
+        let cases = [
+            ("abc אבג", false),
+            ("אבג abc", true),
+            // Digits are weak, the first letter decides.
+            ("123 אבג", true),
+            ("", false),
+        ];
+        for (text, rtl) in cases {
+            assert_eq!(is_rtl_paragraph(text), rtl, "{text}");
+        }
+    }
+
+    #[test]
+    fn reorders_mixed_lines() {
+        let cases: &[(&[&str], bool, &[&str])] = &[
+            (&["abc"], false, &["abc"]),
+            (&["abc אבג"], false, &["abc ", "אבג"]),
+            (&["אבג abc"], true, &["abc", "אבג "]),
+            (&["abc (אבג) d"], false, &["abc (", "אבג", ") d"]),
+            (&["אבג ", "abc"], true, &["abc", "אבג "]),
+            // Brackets without letters are mirrored here, Slint mirrors the
+            // ones next to Hebrew itself.
+            (&["(1)"], true, &["(", "1", ")"]),
+            (&["א (1)"], true, &["(", "1", "א ("]),
+        ];
+        for &(logical, rtl, visual) in cases {
+            let reordered: Vec<String> = reorder_line(runs(logical), rtl)
+                .iter()
+                .map(|run| run.text.to_string())
+                .collect();
+            assert_eq!(reordered, visual, "{logical:?}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_style_of_each_run() {
+        let mut logical = runs(&["אבג ", "abc"]);
+        logical[0].bold = true;
+        let reordered = reorder_line(logical, true);
+        assert!(!reordered[0].bold);
+        assert!(reordered[1].bold);
+    }
+
+    #[test]
+    fn mirrors_brackets() {
+        let cases = [
+            ('(', ')'),
+            (']', '['),
+            ('{', '}'),
+            ('<', '>'),
+            ('«', '»'),
+            ('a', 'a'),
+        ];
+        for (c, mirrored) in cases {
+            assert_eq!(mirror(c), mirrored);
+        }
+    }
+}
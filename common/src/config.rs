@@ -1,31 +1,63 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub general: GeneralConfig,
     pub ankiweb: AnkiWebConfig,
+    /// Where the config was loaded from, and where `save` writes it back.
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeneralConfig {
     pub language: String,
     pub collection_path: String,
+    /// Hide furigana on the question side, so the reading can be recalled.
+    #[serde(default)]
+    pub furigana_answer_only: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnkiWebConfig {
     #[serde(default)]
     pub username: String,
-    #[serde(default)]
+    /// One-shot: exchanged for a token on the next login and never written back.
+    #[serde(default, skip_serializing)]
     pub password: String,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub token: Option<String>,
+    /// Keeps the token in a separate owner-only file instead of config.toml.
+    #[serde(default)]
+    pub secrets_path: Option<String>,
     #[serde(default)]
     pub auto_sync: bool,
     #[serde(default)]
     pub sync_on_exit: bool,
+    /// Base URL of a self-hosted sync server, for collection and media.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Secrets {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    token: Option<String>,
+}
+
+/// The generated config writes `token = ""`, which must not count as a token.
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.filter(|v| !v.trim().is_empty()))
 }
 
 impl Default for Config {
@@ -34,14 +66,18 @@ impl Default for Config {
             general: GeneralConfig {
                 language: "en-GB".into(),
                 collection_path: "/mnt/ext1/applications/pbanki/collection".into(),
+                furigana_answer_only: false,
             },
             ankiweb: AnkiWebConfig {
                 username: String::new(),
                 password: String::new(),
                 token: None,
+                secrets_path: None,
                 auto_sync: false,
                 sync_on_exit: false,
+                endpoint: None,
             },
+            path: None,
         }
     }
 }
@@ -56,6 +92,9 @@ language = "en-GB"
 # Collection path (relative to app directory or absolute)
 collection_path = "/mnt/ext1/applications/pbanki/collection"
 
+# Show furigana readings only on the answer side
+furigana_answer_only = false
+
 [ankiweb]
 # AnkiWeb synchronization settings
 # Leave empty to disable sync
@@ -63,58 +102,227 @@ collection_path = "/mnt/ext1/applications/pbanki/collection"
 # AnkiWeb username (email)
 username = ""
 
-# Password (plain text - will be encrypted in future versions)
-# WARNING: Do not share this file if password is filled
+# Password, only needed once: it is exchanged for a session token on the
+# next sync and then removed from this file. You can also log in from the
+# app when syncing instead.
 password = ""
 
 # Session token (populated after successful login)
 # This avoids storing password long-term
 token = ""
 
+# Store the session token in a separate file readable only by its owner
+# secrets_path = "/mnt/ext1/applications/pbanki/secrets.toml"
+
 # Sync automatically on app start
 auto_sync = false
 
 # Sync automatically after session ends
 sync_on_exit = false
+
+# Self-hosted sync server (e.g. anki-sync-server), leave commented out for AnkiWeb.
+# Media is synced through the same base URL.
+# endpoint = "http://192.168.1.10:8080/"
 "#;
 
 impl Config {
-    pub fn load_or_create(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn load_or_create(path: &str) -> Result<Self> {
         let path = Path::new(path);
 
         if path.exists() {
-            let contents = fs::read_to_string(path)?;
-            let config: Config = toml::from_str(&contents)?;
+            let contents = fs::read_to_string(path).map_err(Error::config)?;
+            let mut config: Config = toml::from_str(&contents).map_err(Error::config)?;
+            config.path = Some(path.to_path_buf());
+            let secrets = config
+                .secrets_path()
+                .map(|p| Self::load_secrets(&p))
+                .transpose()?;
+            if let Some(secrets) = secrets {
+                // Fall back to a token still sitting in config.toml, it moves
+                // over to the secrets file on the next save.
+                config.ankiweb.token = secrets.token.or(config.ankiweb.token.take());
+            }
             Ok(config)
         } else {
-            let config = Config::default();
+            let mut config = Config::default();
             config.save_with_comments(path.to_str().unwrap())?;
+            config.path = Some(path.to_path_buf());
             Ok(config)
         }
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let path = "/mnt/ext1/applications/pbanki/config.toml";
-        let toml_string = toml::to_string_pretty(self)?;
+    /// The file this config was loaded from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = self
+            .path
+            .as_deref()
+            .ok_or_else(|| Error::Config("Config was not loaded from a file".into()))?;
+        self.save_to(path)
+    }
 
-        if let Some(parent) = Path::new(path).parent() {
-            fs::create_dir_all(parent)?;
+    /// Writes the config to `path`, keeping the comments and layout of the
+    /// file already there. The file is replaced atomically, so a power loss
+    /// leaves either the old or the new config behind.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        let mut public = self.clone();
+        if let Some(secrets_path) = self.secrets_path() {
+            Self::save_secrets(
+                &secrets_path,
+                &Secrets {
+                    token: public.ankiweb.token.take(),
+                },
+            )?;
         }
+        let updated: DocumentMut = toml::to_string_pretty(&public)
+            .map_err(Error::config)?
+            .parse()
+            .map_err(Error::config)?;
+
+        let mut document = match fs::read_to_string(path) {
+            Ok(contents) => contents.parse::<DocumentMut>().map_err(Error::config)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DocumentMut::new(),
+            Err(e) => return Err(Error::config(e)),
+        };
+        merge_tables(document.as_table_mut(), updated.as_table());
 
-        fs::write(path, toml_string)?;
+        write_atomic(path, document.to_string().as_bytes(), None).map_err(Error::config)?;
         Ok(())
     }
 
-    fn save_with_comments(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(parent) = Path::new(path).parent() {
-            fs::create_dir_all(parent)?;
+    /// Relative secrets paths are resolved against the config's directory.
+    fn secrets_path(&self) -> Option<PathBuf> {
+        let secrets_path = Path::new(
+            self.ankiweb
+                .secrets_path
+                .as_deref()
+                .filter(|p| !p.trim().is_empty())?,
+        );
+
+        match self.path.as_deref().and_then(Path::parent) {
+            Some(dir) if secrets_path.is_relative() => Some(dir.join(secrets_path)),
+            _ => Some(secrets_path.to_path_buf()),
         }
+    }
 
-        fs::write(path, DEFAULT_CONFIG_WITH_COMMENTS)?;
-        Ok(())
+    fn load_secrets(path: &Path) -> Result<Secrets> {
+        if !path.exists() {
+            return Ok(Secrets::default());
+        }
+        let contents = fs::read_to_string(path).map_err(Error::config)?;
+        toml::from_str(&contents).map_err(Error::config)
+    }
+
+    fn save_secrets(path: &Path, secrets: &Secrets) -> Result<()> {
+        write_atomic(
+            path,
+            toml::to_string_pretty(secrets)
+                .map_err(Error::config)?
+                .as_bytes(),
+            Some(0o600),
+        )
+        .map_err(Error::config)
     }
 
-    pub fn update_and_save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    fn save_with_comments(&self, path: &str) -> Result<()> {
+        write_atomic(
+            Path::new(path),
+            DEFAULT_CONFIG_WITH_COMMENTS.as_bytes(),
+            None,
+        )
+        .map_err(Error::config)
+    }
+
+    pub fn update_and_save(&mut self) -> Result<()> {
         self.save()
     }
 }
+
+/// Copies the values of `src` into `dst` without touching the comments and
+/// whitespace around existing keys.
+fn merge_tables(dst: &mut Table, src: &Table) {
+    let stale: Vec<String> = dst
+        .iter()
+        .filter(|(key, _)| !src.contains_key(key))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    for key in stale {
+        // Blank out strings that are no longer serialized, such as a scrubbed
+        // password, so the comment documenting them survives.
+        match dst.get_mut(&key) {
+            Some(Item::Value(value)) if value.is_str() => {
+                let decor = value.decor().clone();
+                *value = Value::from("");
+                *value.decor_mut() = decor;
+            }
+            _ => {
+                dst.remove(&key);
+            }
+        }
+    }
+
+    for (key, item) in src.iter() {
+        match (dst.get_mut(key), item) {
+            (Some(Item::Table(dst_table)), Item::Table(src_table)) => {
+                merge_tables(dst_table, src_table);
+            }
+            (Some(Item::Value(dst_value)), Item::Value(src_value)) => {
+                let decor = dst_value.decor().clone();
+                *dst_value = src_value.clone();
+                *dst_value.decor_mut() = decor;
+            }
+            _ => {
+                dst.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path`, syncs it and renames
+/// it over `path`.
+fn write_atomic(path: &Path, contents: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    // A leftover from an interrupted save would keep its old permissions.
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    {
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
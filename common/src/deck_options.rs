@@ -0,0 +1,172 @@
+use std::rc::Rc;
+
+use anki::prelude::I18n;
+use anki_proto::deck_config::DeckConfigsForUpdate;
+use anki_proto::deck_config::deck_config::Config;
+use anki_proto::deck_config::deck_configs_for_update::current_deck::Limits;
+use slint::Model;
+
+use crate::{DeckOptions, Step, StepEdit};
+
+/// Learning steps the steppers move between, in minutes. Steps set on the
+/// desktop in between are kept until they are changed.
+const STEP_LADDER: &[f32] = &[
+    1.0, 2.0, 3.0, 5.0, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0, 120.0, 180.0, 360.0, 720.0, 1440.0,
+    2880.0, 4320.0, 7200.0, 10080.0,
+];
+
+const MINUTES_PER_HOUR: f32 = 60.0;
+const MINUTES_PER_DAY: f32 = 1440.0;
+
+/// The options of the preset `configs` has for its current deck, `None` if
+/// the preset can't be found.
+pub fn from_configs(configs: &DeckConfigsForUpdate, tr: &I18n) -> Option<DeckOptions> {
+    let deck = configs.current_deck.as_ref()?;
+    let preset = configs.all_config.iter().find(|preset| {
+        preset
+            .config
+            .as_ref()
+            .is_some_and(|config| config.id == deck.config_id)
+    })?;
+    let deck_config = preset.config.as_ref()?;
+    let config = deck_config.config.as_ref()?;
+    let limits = deck.limits.as_ref().cloned().unwrap_or_default();
+
+    Some(DeckOptions {
+        preset: format!(
+            "{} ({})",
+            deck_config.name,
+            tr.deck_config_used_by_decks(preset.use_count)
+        )
+        .into(),
+        new_per_day: config.new_per_day as i32,
+        reviews_per_day: config.reviews_per_day as i32,
+        new_today: limits.new_today.unwrap_or(config.new_per_day) as i32,
+        new_today_active: limits.new_today_active,
+        reviews_today: limits.review_today.unwrap_or(config.reviews_per_day) as i32,
+        reviews_today_active: limits.review_today_active,
+        learn_steps: steps(&config.learn_steps),
+        relearn_steps: steps(&config.relearn_steps),
+        graduating_interval: config.graduating_interval_good as i32,
+        easy_interval: config.graduating_interval_easy as i32,
+        maximum_interval: config.maximum_review_interval as i32,
+        fsrs: configs.fsrs,
+        desired_retention: (config.desired_retention * 100.0).round() as i32,
+        bury_new: config.bury_new,
+        bury_reviews: config.bury_reviews,
+        bury_interday_learning: config.bury_interday_learning,
+    })
+}
+
+/// Puts the edited options into the preset, leaving everything the screen
+/// doesn't show as it was.
+pub fn apply(options: &DeckOptions, config: &mut Config) {
+    config.new_per_day = options.new_per_day.max(0) as u32;
+    config.reviews_per_day = options.reviews_per_day.max(0) as u32;
+    config.learn_steps = options
+        .learn_steps
+        .iter()
+        .map(|step| step.minutes)
+        .collect();
+    config.relearn_steps = options
+        .relearn_steps
+        .iter()
+        .map(|step| step.minutes)
+        .collect();
+    config.graduating_interval_good = options.graduating_interval.max(1) as u32;
+    config.graduating_interval_easy = options.easy_interval.max(1) as u32;
+    config.maximum_review_interval = options.maximum_interval.max(1) as u32;
+    if options.fsrs {
+        config.desired_retention = options.desired_retention as f32 / 100.0;
+    }
+    config.bury_new = options.bury_new;
+    config.bury_reviews = options.bury_reviews;
+    config.bury_interday_learning = options.bury_interday_learning;
+}
+
+/// The deck's own limits with the "today only" ones from the screen. The
+/// counts of inactive overrides are kept, as Anki does.
+pub fn apply_limits(options: &DeckOptions, limits: &mut Limits) {
+    limits.new_today = Some(options.new_today.max(0) as u32);
+    limits.new_today_active = options.new_today_active;
+    limits.review_today = Some(options.reviews_today.max(0) as u32);
+    limits.review_today_active = options.reviews_today_active;
+}
+
+/// Makes a step shorter or longer along [`STEP_LADDER`], or adds one after
+/// the last step or removes it.
+pub fn edit_steps(
+    steps: &slint::ModelRc<Step>,
+    index: usize,
+    edit: StepEdit,
+) -> slint::ModelRc<Step> {
+    let mut minutes: Vec<f32> = steps.iter().map(|step| step.minutes).collect();
+
+    match edit {
+        StepEdit::Shorter => {
+            if let Some(step) = minutes.get_mut(index) {
+                *step = STEP_LADDER
+                    .iter()
+                    .rev()
+                    .copied()
+                    .find(|&rung| rung < *step)
+                    .unwrap_or(*step);
+            }
+        }
+        StepEdit::Longer => {
+            if let Some(step) = minutes.get_mut(index) {
+                *step = STEP_LADDER
+                    .iter()
+                    .copied()
+                    .find(|&rung| rung > *step)
+                    .unwrap_or(*step);
+            }
+        }
+        StepEdit::Add => {
+            let last = minutes.last().copied().unwrap_or(0.0);
+            let next = STEP_LADDER
+                .iter()
+                .copied()
+                .find(|&rung| rung > last)
+                .unwrap_or(last);
+            minutes.push(next);
+        }
+        StepEdit::Remove => {
+            if index < minutes.len() {
+                minutes.remove(index);
+            }
+        }
+    }
+
+    self::steps(&minutes)
+}
+
+fn steps(minutes: &[f32]) -> slint::ModelRc<Step> {
+    Rc::new(slint::VecModel::from(
+        minutes.iter().copied().map(step).collect::<Vec<_>>(),
+    ))
+    .into()
+}
+
+fn step(minutes: f32) -> Step {
+    Step {
+        minutes,
+        label: step_label(minutes).into(),
+    }
+}
+
+/// Like Anki's step input: "30s", "10m", "1h", "1.5d".
+fn step_label(minutes: f32) -> String {
+    let (value, unit) = if minutes < 1.0 {
+        (minutes * 60.0, "s")
+    } else if minutes < MINUTES_PER_HOUR {
+        (minutes, "m")
+    } else if minutes < MINUTES_PER_DAY {
+        (minutes / MINUTES_PER_HOUR, "h")
+    } else {
+        (minutes / MINUTES_PER_DAY, "d")
+    };
+
+    let value = (value * 10.0).round() / 10.0;
+    format!("{}{}", value, unit)
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+
+/// Everything the common crate can fail with, grouped by what the user was
+/// doing, so the UI can show a message instead of the app going away.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Opening or reading the collection failed.
+    Collection(String),
+    /// Turning a card into displayable text failed.
+    Render(String),
+    /// Building the queue or recording an answer failed.
+    Scheduling(String),
+    /// Logging in to the sync server or syncing with it failed.
+    Sync(String),
+    /// Reading or saving the config file failed.
+    Config(String),
+    /// The collection is owned by the sync worker until the sync finishes.
+    CollectionBusy,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn collection(e: impl fmt::Display) -> Self {
+        Error::Collection(e.to_string())
+    }
+
+    pub(crate) fn render(e: impl fmt::Display) -> Self {
+        Error::Render(e.to_string())
+    }
+
+    pub(crate) fn scheduling(e: impl fmt::Display) -> Self {
+        Error::Scheduling(e.to_string())
+    }
+
+    pub(crate) fn sync(e: impl fmt::Display) -> Self {
+        Error::Sync(e.to_string())
+    }
+
+    pub(crate) fn config(e: impl fmt::Display) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Collection(message) => write!(f, "Collection error: {}", message),
+            Error::Render(message) => write!(f, "Could not display card: {}", message),
+            Error::Scheduling(message) => write!(f, "Scheduling error: {}", message),
+            Error::Sync(message) => write!(f, "Sync error: {}", message),
+            Error::Config(message) => write!(f, "Config error: {}", message),
+            Error::CollectionBusy => {
+                write!(
+                    f,
+                    "The collection is busy syncing, try again once it has finished"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
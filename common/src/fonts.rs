@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use fontdb::{Database, Family, Query, Style, Weight};
+use rustybuzz::{Face, UnicodeBuffer};
+
+use crate::{PageLayout, TextRun};
+
+/// Used when no font can be found at all, Slint's default monospace estimate.
+const FALLBACK_ADVANCE_EM: f32 = 0.6;
+const FALLBACK_HEIGHT_EM: f32 = 1.3;
+
+/// The system fonts, looked up the way Slint's software renderer does:
+/// `SLINT_DEFAULT_FONT` replaces the default family if set, and a family
+/// that isn't installed falls back to the default one.
+static FONT_DB: LazyLock<FontDatabase> = LazyLock::new(FontDatabase::load);
+
+/// Parsed faces by id. Their data is leaked, there are only ever a handful.
+static FACES: LazyLock<Mutex<HashMap<fontdb::ID, Option<Arc<Face<'static>>>>>> =
+    LazyLock::new(Default::default);
+
+struct FontDatabase {
+    db: Database,
+    default_families: Vec<String>,
+}
+
+impl FontDatabase {
+    fn load() -> Self {
+        let mut db = Database::new();
+        let mut default_families = Vec::new();
+
+        if let Some(path) = std::env::var_os("SLINT_DEFAULT_FONT") {
+            let path = std::path::Path::new(&path);
+            let loaded = if path.extension().is_some() {
+                db.load_font_file(path).is_ok()
+            } else {
+                db.load_fonts_dir(path);
+                true
+            };
+            if loaded {
+                for face in db.faces() {
+                    if let Some((name, _)) = face.families.first()
+                        && !default_families.contains(name)
+                    {
+                        default_families.push(name.clone());
+                    }
+                }
+            }
+        }
+
+        db.load_system_fonts();
+        FontDatabase {
+            db,
+            default_families,
+        }
+    }
+
+    fn query(&self, family: Option<&str>, bold: bool, italic: bool) -> Option<fontdb::ID> {
+        let names: Vec<Family> = match family {
+            Some(family) => vec![Family::Name(family)],
+            None if self.default_families.is_empty() => vec![Family::SansSerif],
+            None => self
+                .default_families
+                .iter()
+                .map(|name| Family::Name(name))
+                .collect(),
+        };
+
+        self.db.query(&Query {
+            families: &names,
+            weight: if bold { Weight::BOLD } else { Weight::NORMAL },
+            style: if italic { Style::Italic } else { Style::Normal },
+            ..Default::default()
+        })
+    }
+
+    fn face(&self, family: Option<&str>, bold: bool, italic: bool) -> Option<Arc<Face<'static>>> {
+        let id = self.query(family, bold, italic)?;
+        let mut faces = FACES.lock().unwrap();
+
+        faces
+            .entry(id)
+            .or_insert_with(|| {
+                self.db
+                    .with_face_data(id, |data, index| {
+                        let data: &'static [u8] = Box::leak(data.to_vec().into_boxed_slice());
+                        Face::from_slice(data, index).map(Arc::new)
+                    })
+                    .flatten()
+            })
+            .clone()
+    }
+}
+
+struct FontFace {
+    face: Option<Arc<Face<'static>>>,
+    /// Used for characters `face` has no glyph for.
+    fallback: Option<Arc<Face<'static>>>,
+}
+
+/// Measures text in physical pixels as Slint's software renderer lays it out:
+/// shaped with rustybuzz, with glyph advances and line heights truncated to
+/// whole pixels.
+pub struct TextMeasure {
+    /// Indexed by `bold | italic << 1`.
+    faces: [FontFace; 4],
+    scale_factor: f32,
+    reveal_clozes: bool,
+    content_size: f32,
+    heading_size: f32,
+    ruby_size: f32,
+}
+
+impl TextMeasure {
+    pub fn new(layout: &PageLayout) -> Self {
+        let family = layout.font_family.as_str();
+        let family = (!family.is_empty()).then_some(family);
+        let scale_factor = if layout.scale_factor > 0.0 {
+            layout.scale_factor
+        } else {
+            1.0
+        };
+
+        let faces = std::array::from_fn(|i| {
+            let (bold, italic) = (i & 1 != 0, i & 2 != 0);
+            let fallback = FONT_DB.face(None, bold, italic);
+            FontFace {
+                face: family
+                    .and_then(|family| FONT_DB.face(Some(family), bold, italic))
+                    .or_else(|| fallback.clone()),
+                fallback,
+            }
+        });
+
+        TextMeasure {
+            faces,
+            scale_factor,
+            reveal_clozes: layout.reveal_clozes,
+            content_size: (layout.font_size * scale_factor).trunc(),
+            heading_size: (layout.heading_font_size * scale_factor).trunc(),
+            ruby_size: (layout.ruby_font_size * scale_factor).trunc(),
+        }
+    }
+
+    /// Converts a logical length from the UI into physical pixels.
+    pub fn physical(&self, logical: f32) -> f32 {
+        logical * self.scale_factor
+    }
+
+    fn font(&self, run: &TextRun) -> (&FontFace, f32) {
+        // Revealed clozes are bold, see rich_text.slint.
+        let bold = run.bold || (run.cloze && self.reveal_clozes);
+        let index = bold as usize | (run.italic as usize) << 1;
+        let size = if run.heading {
+            self.heading_size
+        } else {
+            self.content_size
+        };
+        (&self.faces[index], size)
+    }
+
+    /// Width of `text` set in the style of `run`, or of its reading if that
+    /// is wider.
+    pub fn width(&self, run: &TextRun, text: &str) -> f32 {
+        let (font, size) = self.font(run);
+        let width = text_width(font, size, text);
+        if run.ruby.is_empty() {
+            width
+        } else {
+            width.max(text_width(&self.faces[0], self.ruby_size, &run.ruby))
+        }
+    }
+
+    /// Height of a line of text set in the style of `run`.
+    pub fn line_height(&self, run: &TextRun) -> f32 {
+        let (font, size) = self.font(run);
+        line_height(font, size)
+    }
+
+    /// Height of the readings above ruby text.
+    pub fn ruby_height(&self) -> f32 {
+        line_height(&self.faces[0], self.ruby_size)
+    }
+}
+
+fn text_width(font: &FontFace, size: f32, text: &str) -> f32 {
+    let Some(face) = &font.face else {
+        return text.chars().count() as f32 * (size * FALLBACK_ADVANCE_EM).trunc();
+    };
+
+    let mut width = 0.0;
+    let mut missing = String::new();
+    for (cluster, advance) in shape(face, size, text) {
+        match advance {
+            Some(advance) => width += advance,
+            None => missing.push_str(cluster),
+        }
+    }
+
+    // Characters the font lacks are drawn with the fallback font, or as the
+    // missing glyph box.
+    if !missing.is_empty() {
+        let fallback = font.fallback.as_ref().unwrap_or(face);
+        for (cluster, advance) in shape(fallback, size, &missing) {
+            width += advance
+                .unwrap_or_else(|| notdef_advance(face, size) * cluster.chars().count() as f32);
+        }
+    }
+
+    width
+}
+
+fn line_height(font: &FontFace, size: f32) -> f32 {
+    match &font.face {
+        Some(face) => (face.height() as f32 * size / face.units_per_em() as f32).trunc(),
+        None => (size * FALLBACK_HEIGHT_EM).trunc(),
+    }
+}
+
+/// Shapes `text` and returns each cluster with its advance, `None` for
+/// clusters the face has no glyphs for.
+fn shape<'t>(face: &Face, size: f32, text: &'t str) -> Vec<(&'t str, Option<f32>)> {
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.guess_segment_properties();
+
+    let glyphs = rustybuzz::shape(face, &[], buffer);
+    let scale = size / face.units_per_em() as f32;
+
+    // Ligatures and combining marks put several glyphs into one cluster.
+    let mut clusters: BTreeMap<usize, Option<f32>> = BTreeMap::new();
+    for (info, position) in glyphs.glyph_infos().iter().zip(glyphs.glyph_positions()) {
+        let advance = (info.glyph_id != 0).then(|| (position.x_advance as f32 * scale).trunc());
+        clusters
+            .entry(info.cluster as usize)
+            .and_modify(|total| *total = total.zip(advance).map(|(a, b)| a + b))
+            .or_insert(advance);
+    }
+
+    let ends = clusters.keys().skip(1).copied().chain([text.len()]);
+    clusters
+        .iter()
+        .zip(ends)
+        .map(|((&start, &advance), end)| (&text[start..end], advance))
+        .collect()
+}
+
+fn notdef_advance(face: &Face, size: f32) -> f32 {
+    face.glyph_hor_advance(rustybuzz::ttf_parser::GlyphId(0))
+        .map_or((size * FALLBACK_ADVANCE_EM).trunc(), |advance| {
+            (advance as f32 * size / face.units_per_em() as f32).trunc()
+        })
+}
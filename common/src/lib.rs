@@ -1,12 +1,26 @@
 slint::include_modules!();
 
 pub mod api;
+pub mod bidi;
 pub mod config;
+pub mod deck_options;
+pub mod error;
+pub mod fonts;
+pub mod math;
+pub mod media;
+pub mod occlusion;
+pub mod session_stats;
+pub mod stats;
 pub mod sync;
+pub mod type_answer;
 pub mod utils;
 
 pub use api::{
-    LearnSession, init_session, init_translations, next_card, rate_card, update_deck_tree,
+    LearnSession, SyncJob, card_action, check_answer, custom_study, custom_study_defaults,
+    deck_options, deck_stats, filtered_deck_action, init_session, init_translations, next_card,
+    rate_card, save_deck_options, set_flag, set_marked, start_session, start_sync, sync_ankiweb,
+    undo, update_deck_tree, wait_for_sync,
 };
 pub use config::Config;
+pub use error::Error;
 pub use sync::{SyncManager, SyncResult, SyncStatus};
@@ -0,0 +1,667 @@
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+
+/// MathJax `\(..\)` / `\[..\]` and the legacy `[latex]`, `[$]` and `[$$]`
+/// blocks. Display math gets a block of its own.
+static MATH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?is)\\\((?P<inline>.*?)\\\)|\\\[(?P<display>.*?)\\\]|\[latex\](?P<latex>.*?)\[/latex\]|\[\$\$\](?P<dollars>.*?)\[/\$\$\]|\[\$\](?P<dollar>.*?)\[/\$\]",
+    )
+    .unwrap()
+});
+
+/// Private use characters around the content of `\class{cloze}{..}`, which
+/// `render_math` turns into a cloze span.
+const CLOZE_START: char = '\u{E000}';
+const CLOZE_END: char = '\u{E001}';
+
+/// Replaces the math in card HTML by a Unicode rendering of it, as the
+/// software renderer has no way to typeset TeX.
+pub fn render_math(html: &str) -> Cow<'_, str> {
+    MATH.replace_all(html, |caps: &Captures| {
+        if let Some(tex) = caps.name("inline").or_else(|| caps.name("dollar")) {
+            lines_to_html(&render_tex(&anki::text::strip_html(tex.as_str())))
+        } else {
+            let tex = caps
+                .name("display")
+                .or_else(|| caps.name("latex"))
+                .or_else(|| caps.name("dollars"))
+                .map_or("", |m| m.as_str());
+            format!(
+                "<div>{}</div>",
+                lines_to_html(&render_tex(&anki::text::strip_html(tex)))
+            )
+        }
+    })
+}
+
+/// Escapes the rendered formula, keeping its lines apart with `<br>` and
+/// its clozes in the span Anki puts them in.
+fn lines_to_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\n', "<br>")
+        .replace(CLOZE_START, r#"<span class="cloze">"#)
+        .replace(CLOZE_END, "</span>")
+}
+
+/// Renders a TeX formula as plain Unicode text. Covers fractions, roots,
+/// sub- and superscripts, Greek letters and the common operators; anything
+/// else is shown close to its source. The rows of `\\` are separate lines.
+pub fn tex_to_unicode(tex: &str) -> String {
+    render_tex(tex)
+        .chars()
+        .filter(|&c| c != CLOZE_START && c != CLOZE_END)
+        .collect()
+}
+
+/// Like [`tex_to_unicode`], with clozes between [`CLOZE_START`] and
+/// [`CLOZE_END`].
+fn render_tex(tex: &str) -> String {
+    let mut parser = Parser {
+        chars: tex.chars().collect(),
+        pos: 0,
+    };
+    let rendered = parser.parse_until(None);
+
+    rendered
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "max", "min", "sup", "inf", "det", "deg", "dim", "gcd", "arg",
+    "ker", "Pr",
+];
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses up to (and consumes) `end`, or to the end of the input.
+    fn parse_until(&mut self, end: Option<char>) -> String {
+        let mut out = String::new();
+
+        while let Some(c) = self.peek() {
+            if Some(c) == end {
+                self.pos += 1;
+                break;
+            }
+            if let Some(atom) = self.parse_atom() {
+                out.push_str(&atom);
+            }
+        }
+
+        out
+    }
+
+    /// A single argument: a `{group}`, a command or one character.
+    fn parse_argument(&mut self) -> String {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                self.parse_until(Some('}'))
+            }
+            Some(_) => self.parse_atom().unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    fn parse_optional_argument(&mut self) -> Option<String> {
+        self.skip_whitespace();
+        if self.peek() == Some('[') {
+            self.pos += 1;
+            Some(self.parse_until(Some(']')))
+        } else {
+            None
+        }
+    }
+
+    fn parse_atom(&mut self) -> Option<String> {
+        let c = self.next()?;
+
+        let atom = match c {
+            '{' => self.parse_until(Some('}')),
+            '^' => superscript(&self.parse_argument()),
+            '_' => subscript(&self.parse_argument()),
+            '\\' => self.parse_command(),
+            '\'' => "′".into(),
+            '~' | '&' => " ".into(),
+            '$' | '}' => return None,
+            c if c.is_whitespace() => " ".into(),
+            c => c.to_string(),
+        };
+
+        Some(atom)
+    }
+
+    fn parse_command(&mut self) -> String {
+        let Some(first) = self.next() else {
+            return String::new();
+        };
+
+        if !first.is_ascii_alphabetic() {
+            return match first {
+                '\\' => "\n".into(),
+                ',' | ':' | ';' | ' ' => " ".into(),
+                '!' => String::new(),
+                '|' => "‖".into(),
+                c => c.to_string(),
+            };
+        }
+
+        let mut name = String::from(first);
+        while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+            name.push(c);
+            self.pos += 1;
+        }
+        // As in TeX, spaces after a control word only end its name.
+        self.skip_whitespace();
+
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.parse_argument();
+                let denominator = self.parse_argument();
+                fraction(&numerator, &denominator)
+            }
+            "sqrt" => {
+                let index = self.parse_optional_argument();
+                let radicand = self.parse_argument();
+                let sign = match index.as_deref().map(str::trim) {
+                    None | Some("") | Some("2") => "√".to_string(),
+                    Some("3") => "∛".to_string(),
+                    Some("4") => "∜".to_string(),
+                    Some(n) => format!("{}√", superscript(n)),
+                };
+                format!("{}{}", sign, group(&radicand))
+            }
+            "text" | "textrm" | "textnormal" | "textit" | "textbf" | "texttt" | "mathrm"
+            | "mathit" | "mathbf" | "mathsf" | "mathtt" | "mathcal" | "mathscr" | "mathfrak"
+            | "boldsymbol" | "bm" | "operatorname" | "mbox" | "emph" => self.parse_argument(),
+            // Clozes inside MathJax are wrapped in `\class{cloze}{..}`.
+            "class" | "cssId" | "style" => {
+                let class = self.parse_argument();
+                let content = self.parse_argument();
+                if name == "class" && class.split_whitespace().any(|c| c == "cloze") {
+                    format!("{}{}{}", CLOZE_START, content, CLOZE_END)
+                } else {
+                    content
+                }
+            }
+            "mathbb" => self.parse_argument().chars().map(double_struck).collect(),
+            "hat" | "widehat" => combine(&self.parse_argument(), '\u{0302}'),
+            "bar" | "overline" => combine(&self.parse_argument(), '\u{0305}'),
+            "vec" | "overrightarrow" => combine(&self.parse_argument(), '\u{20D7}'),
+            "dot" => combine(&self.parse_argument(), '\u{0307}'),
+            "ddot" => combine(&self.parse_argument(), '\u{0308}'),
+            "tilde" | "widetilde" => combine(&self.parse_argument(), '\u{0303}'),
+            "underline" => combine(&self.parse_argument(), '\u{0332}'),
+            "left" | "right" | "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl"
+            | "Bigr" | "biggl" | "biggr" | "middle" => {
+                self.skip_whitespace();
+                // `\left.` is an invisible delimiter.
+                if self.peek() == Some('.') {
+                    self.pos += 1;
+                }
+                String::new()
+            }
+            "begin" | "end" => {
+                self.parse_argument();
+                " ".into()
+            }
+            "quad" | "qquad" | "enspace" | "thinspace" => " ".into(),
+            "limits" | "nolimits" | "nonumber" | "notag" | "displaystyle" | "textstyle" => {
+                String::new()
+            }
+            name if FUNCTIONS.contains(&name) => {
+                // Keep `\sin x` from turning into "sinx".
+                if self
+                    .peek()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '\\')
+                {
+                    format!("{} ", name)
+                } else {
+                    name.to_string()
+                }
+            }
+            name => {
+                if let Some(relation) = relation(name) {
+                    format!(" {} ", relation)
+                } else {
+                    symbol(name).map_or_else(|| name.to_string(), str::to_string)
+                }
+            }
+        }
+    }
+}
+
+/// Wraps anything longer than a single term in parentheses.
+fn group(s: &str) -> String {
+    let s = s.trim();
+    if s.chars().count() <= 1 || s.chars().all(|c| c.is_alphanumeric() || c == '.') {
+        s.to_string()
+    } else {
+        format!("({})", s)
+    }
+}
+
+fn fraction(numerator: &str, denominator: &str) -> String {
+    let (numerator, denominator) = (numerator.trim(), denominator.trim());
+
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if is_number(numerator) && is_number(denominator) {
+        return format!("{}⁄{}", superscript(numerator), subscript(denominator));
+    }
+
+    format!("{}/{}", group(numerator), group(denominator))
+}
+
+fn combine(s: &str, mark: char) -> String {
+    s.chars()
+        .flat_map(|c| {
+            if c.is_whitespace() {
+                vec![c]
+            } else {
+                vec![c, mark]
+            }
+        })
+        .collect()
+}
+
+fn superscript(s: &str) -> String {
+    script(s, '^', superscript_char)
+}
+
+fn subscript(s: &str) -> String {
+    script(s, '_', subscript_char)
+}
+
+/// Uses the Unicode super-/subscript forms if every character has one, the
+/// TeX notation otherwise.
+fn script(s: &str, marker: char, map: fn(char) -> Option<char>) -> String {
+    let s = s.trim();
+    if let Some(mapped) = s.chars().map(map).collect::<Option<String>>() {
+        return mapped;
+    }
+
+    if s.chars().count() == 1 {
+        format!("{}{}", marker, s)
+    } else {
+        format!("{}({})", marker, s)
+    }
+}
+
+fn superscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '⁰',
+        '1' => '¹',
+        '2' => '²',
+        '3' => '³',
+        '4' => '⁴',
+        '5' => '⁵',
+        '6' => '⁶',
+        '7' => '⁷',
+        '8' => '⁸',
+        '9' => '⁹',
+        '+' => '⁺',
+        '-' | '−' => '⁻',
+        '=' => '⁼',
+        '(' => '⁽',
+        ')' => '⁾',
+        '′' | '″' | '*' | '∗' | '∘' | '†' => c,
+        'a' => 'ᵃ',
+        'b' => 'ᵇ',
+        'c' => 'ᶜ',
+        'd' => 'ᵈ',
+        'e' => 'ᵉ',
+        'f' => 'ᶠ',
+        'g' => 'ᵍ',
+        'h' => 'ʰ',
+        'i' => 'ⁱ',
+        'j' => 'ʲ',
+        'k' => 'ᵏ',
+        'l' => 'ˡ',
+        'm' => 'ᵐ',
+        'n' => 'ⁿ',
+        'o' => 'ᵒ',
+        'p' => 'ᵖ',
+        'r' => 'ʳ',
+        's' => 'ˢ',
+        't' => 'ᵗ',
+        'u' => 'ᵘ',
+        'v' => 'ᵛ',
+        'w' => 'ʷ',
+        'x' => 'ˣ',
+        'y' => 'ʸ',
+        'z' => 'ᶻ',
+        'A' => 'ᴬ',
+        'B' => 'ᴮ',
+        'D' => 'ᴰ',
+        'E' => 'ᴱ',
+        'G' => 'ᴳ',
+        'H' => 'ᴴ',
+        'I' => 'ᴵ',
+        'J' => 'ᴶ',
+        'K' => 'ᴷ',
+        'L' => 'ᴸ',
+        'M' => 'ᴹ',
+        'N' => 'ᴺ',
+        'O' => 'ᴼ',
+        'P' => 'ᴾ',
+        'R' => 'ᴿ',
+        'T' => 'ᵀ',
+        'U' => 'ᵁ',
+        'V' => 'ⱽ',
+        'W' => 'ᵂ',
+        'α' => 'ᵅ',
+        'β' => 'ᵝ',
+        'γ' => 'ᵞ',
+        'δ' => 'ᵟ',
+        'ε' => 'ᵋ',
+        'θ' => 'ᶿ',
+        'φ' => 'ᵠ',
+        'χ' => 'ᵡ',
+        _ => return None,
+    })
+}
+
+fn subscript_char(c: char) -> Option<char> {
+    Some(match c {
+        '0' => '₀',
+        '1' => '₁',
+        '2' => '₂',
+        '3' => '₃',
+        '4' => '₄',
+        '5' => '₅',
+        '6' => '₆',
+        '7' => '₇',
+        '8' => '₈',
+        '9' => '₉',
+        '+' => '₊',
+        '-' | '−' => '₋',
+        '=' => '₌',
+        '(' => '₍',
+        ')' => '₎',
+        'a' => 'ₐ',
+        'e' => 'ₑ',
+        'h' => 'ₕ',
+        'i' => 'ᵢ',
+        'j' => 'ⱼ',
+        'k' => 'ₖ',
+        'l' => 'ₗ',
+        'm' => 'ₘ',
+        'n' => 'ₙ',
+        'o' => 'ₒ',
+        'p' => 'ₚ',
+        'r' => 'ᵣ',
+        's' => 'ₛ',
+        't' => 'ₜ',
+        'u' => 'ᵤ',
+        'v' => 'ᵥ',
+        'x' => 'ₓ',
+        'β' => 'ᵦ',
+        'γ' => 'ᵧ',
+        'ρ' => 'ᵨ',
+        'φ' => 'ᵩ',
+        'χ' => 'ᵪ',
+        _ => return None,
+    })
+}
+
+fn double_struck(c: char) -> char {
+    match c {
+        'C' => 'ℂ',
+        'H' => 'ℍ',
+        'N' => 'ℕ',
+        'P' => 'ℙ',
+        'Q' => 'ℚ',
+        'R' => 'ℝ',
+        'Z' => 'ℤ',
+        'A'..='Z' => char::from_u32(0x1D538 + (c as u32 - 'A' as u32)).unwrap_or(c),
+        '0'..='9' => char::from_u32(0x1D7D8 + (c as u32 - '0' as u32)).unwrap_or(c),
+        c => c,
+    }
+}
+
+fn symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        // Greek letters
+        "alpha" => "α",
+        "beta" => "β",
+        "gamma" => "γ",
+        "delta" => "δ",
+        "epsilon" => "ϵ",
+        "varepsilon" => "ε",
+        "zeta" => "ζ",
+        "eta" => "η",
+        "theta" => "θ",
+        "vartheta" => "ϑ",
+        "iota" => "ι",
+        "kappa" => "κ",
+        "lambda" => "λ",
+        "mu" => "μ",
+        "nu" => "ν",
+        "xi" => "ξ",
+        "omicron" => "ο",
+        "pi" => "π",
+        "varpi" => "ϖ",
+        "rho" => "ρ",
+        "varrho" => "ϱ",
+        "sigma" => "σ",
+        "varsigma" => "ς",
+        "tau" => "τ",
+        "upsilon" => "υ",
+        "phi" => "ϕ",
+        "varphi" => "φ",
+        "chi" => "χ",
+        "psi" => "ψ",
+        "omega" => "ω",
+        "Gamma" => "Γ",
+        "Delta" => "Δ",
+        "Theta" => "Θ",
+        "Lambda" => "Λ",
+        "Xi" => "Ξ",
+        "Pi" => "Π",
+        "Sigma" => "Σ",
+        "Upsilon" => "Υ",
+        "Phi" => "Φ",
+        "Psi" => "Ψ",
+        "Omega" => "Ω",
+        // Operators
+        "times" => "×",
+        "cdot" => "·",
+        "div" => "÷",
+        "pm" => "±",
+        "mp" => "∓",
+        "ast" => "∗",
+        "star" => "⋆",
+        "circ" => "∘",
+        "bullet" => "∙",
+        "oplus" => "⊕",
+        "otimes" => "⊗",
+        "emptyset" | "varnothing" => "∅",
+        "forall" => "∀",
+        "exists" => "∃",
+        "neg" | "lnot" => "¬",
+        // Big operators
+        "sum" => "∑",
+        "prod" => "∏",
+        "coprod" => "∐",
+        "int" => "∫",
+        "iint" => "∬",
+        "iiint" => "∭",
+        "oint" => "∮",
+        "bigcup" => "⋃",
+        "bigcap" => "⋂",
+        // Other symbols
+        "infty" => "∞",
+        "partial" => "∂",
+        "nabla" => "∇",
+        "hbar" => "ℏ",
+        "ell" => "ℓ",
+        "Re" => "ℜ",
+        "Im" => "ℑ",
+        "aleph" => "ℵ",
+        "angle" => "∠",
+        "degree" => "°",
+        "prime" => "′",
+        "dots" | "ldots" => "…",
+        "cdots" => "⋯",
+        "vdots" => "⋮",
+        "ddots" => "⋱",
+        "therefore" => "∴",
+        "because" => "∵",
+        "langle" => "⟨",
+        "rangle" => "⟩",
+        "lfloor" => "⌊",
+        "rfloor" => "⌋",
+        "lceil" => "⌈",
+        "rceil" => "⌉",
+        "vert" => "|",
+        "Vert" => "‖",
+        "lbrace" => "{",
+        "rbrace" => "}",
+        "backslash" => "\\",
+        "dagger" => "†",
+        _ => return None,
+    })
+}
+
+/// Relations and arrows, which get some space around them.
+fn relation(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "leq" | "le" => "≤",
+        "geq" | "ge" => "≥",
+        "neq" | "ne" => "≠",
+        "ll" => "≪",
+        "gg" => "≫",
+        "approx" => "≈",
+        "sim" => "∼",
+        "simeq" => "≃",
+        "cong" => "≅",
+        "equiv" => "≡",
+        "propto" => "∝",
+        "perp" => "⊥",
+        "parallel" => "∥",
+        "mid" => "∣",
+        "in" => "∈",
+        "notin" => "∉",
+        "ni" => "∋",
+        "subset" => "⊂",
+        "subseteq" => "⊆",
+        "supset" => "⊃",
+        "supseteq" => "⊇",
+        "cup" => "∪",
+        "cap" => "∩",
+        "setminus" => "∖",
+        "land" | "wedge" => "∧",
+        "lor" | "vee" => "∨",
+        // Arrows
+        "to" | "rightarrow" => "→",
+        "leftarrow" | "gets" => "←",
+        "leftrightarrow" => "↔",
+        "Rightarrow" | "implies" => "⇒",
+        "Leftarrow" => "⇐",
+        "Leftrightarrow" | "iff" => "⇔",
+        "mapsto" => "↦",
+        "uparrow" => "↑",
+        "downarrow" => "↓",
+        "rightleftharpoons" => "⇌",
+        "longrightarrow" => "⟶",
+        "longleftarrow" => "⟵",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_inline_and_display_math() {
+        assert_eq!(render_math(r"a \(x^2\) b"), "a x² b");
+        assert_eq!(render_math(r"\(a\) and \(b\)"), "a and b");
+        assert_eq!(render_math(r"\[x_1\]"), "<div>x₁</div>");
+        assert_eq!(render_math("no math"), "no math");
+    }
+
+    #[test]
+    fn finds_legacy_latex_blocks() {
+        assert_eq!(render_math(r"[$]\frac{1}{2}[/$]"), "¹⁄₂");
+        assert_eq!(render_math(r"[$$]x[/$$]"), "<div>x</div>");
+        assert_eq!(render_math(r"[latex]a<b[/latex]"), "<div>a&lt;b</div>");
+    }
+
+    #[test]
+    fn keeps_clozes_in_math() {
+        assert_eq!(
+            render_math(r"\(x = \class{cloze}{y^2}\)"),
+            r#"x = <span class="cloze">y²</span>"#
+        );
+        assert_eq!(tex_to_unicode(r"\class{cloze}{[...]}"), "[...]");
+        assert_eq!(tex_to_unicode(r"\class{other}{a}"), "a");
+    }
+
+    #[test]
+    fn keeps_rows_of_display_math_apart() {
+        assert_eq!(render_math(r"\[a \\ b\]"), "<div>a<br>b</div>");
+    }
+
+    #[test]
+    fn fractions() {
+        assert_eq!(tex_to_unicode(r"\frac{1}{2}"), "¹⁄₂");
+        assert_eq!(tex_to_unicode(r"\frac{x}{y}"), "x/y");
+        assert_eq!(tex_to_unicode(r"\frac{a+b}{c}"), "(a+b)/c");
+    }
+
+    #[test]
+    fn scripts() {
+        assert_eq!(tex_to_unicode("x^2"), "x²");
+        assert_eq!(tex_to_unicode("x_i"), "xᵢ");
+        assert_eq!(tex_to_unicode("x^{n+1}"), "xⁿ⁺¹");
+        // Without a Unicode form for every character the TeX notation stays.
+        assert_eq!(tex_to_unicode(r"e^{i\pi}"), "e^(iπ)");
+    }
+
+    #[test]
+    fn line_breaks() {
+        assert_eq!(
+            tex_to_unicode(r"\begin{aligned} a &= b \\ c &= d \end{aligned}"),
+            "a = b\nc = d"
+        );
+        assert_eq!(tex_to_unicode(r"a \\ \\ b"), "a\nb");
+    }
+
+    #[test]
+    fn symbols_and_unknown_commands() {
+        assert_eq!(tex_to_unicode(r"\alpha \leq \beta"), "α ≤ β");
+        assert_eq!(tex_to_unicode(r"\sin x"), "sin x");
+        assert_eq!(tex_to_unicode(r"a + \foo"), "a + foo");
+    }
+}
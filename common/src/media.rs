@@ -0,0 +1,214 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use resvg::{tiny_skia, usvg};
+use slint::{Rgb8Pixel, SharedPixelBuffer};
+
+use crate::error::{Error, Result};
+
+/// Gray levels the PocketBook panels can show.
+const GRAY_LEVELS: u32 = 16;
+
+/// Small images are enlarged at most this much, further only adds blur.
+const MAX_UPSCALE: f32 = 2.0;
+
+pub fn media_folder(collection_path: &str) -> PathBuf {
+    Path::new(collection_path).join("collection.media")
+}
+
+/// Maps an `<img src>` value onto a file in the media folder. Remote images
+/// and anything trying to leave the folder are ignored.
+pub fn resolve(media_folder: &Path, src: &str) -> Option<PathBuf> {
+    if src.contains("://") || src.starts_with("data:") {
+        return None;
+    }
+
+    let name = percent_decode(src);
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name == ".." {
+        return None;
+    }
+
+    Some(media_folder.join(name))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+            out.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decodes an image, fits it into `max_width` x `max_height` and reduces it
+/// to dithered grayscale for the e-ink panel.
+pub fn load_image(path: &Path, max_width: u32, max_height: u32) -> Result<slint::Image> {
+    load_image_with_overlay(path, max_width, max_height, |_, _| None)
+}
+
+/// Like [`load_image`], but draws an SVG over the image before dithering.
+/// `overlay` gets the image's original size and returns the SVG document,
+/// which is stretched over the whole image.
+pub fn load_image_with_overlay(
+    path: &Path,
+    max_width: u32,
+    max_height: u32,
+    overlay: impl FnOnce(u32, u32) -> Option<Vec<u8>>,
+) -> Result<slint::Image> {
+    let data = fs::read(path)
+        .map_err(|e| Error::Render(format!("Cannot read {}: {}", path.display(), e)))?;
+
+    let is_svg = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+
+    let (original, width, height, mut rgba) = if is_svg {
+        decode_svg(&data, max_width, max_height)?
+    } else {
+        decode_raster(&data, max_width, max_height)?
+    };
+
+    if let Some(svg) = overlay(original.0, original.1) {
+        draw_overlay(&svg, width, height, &mut rgba)?;
+    }
+
+    let rgb = dither(&rgba, width, height);
+
+    Ok(slint::Image::from_rgb8(
+        SharedPixelBuffer::<Rgb8Pixel>::clone_from_slice(&rgb, width, height),
+    ))
+}
+
+fn fit(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let scale = (max_width as f32 / width.max(1) as f32)
+        .min(max_height as f32 / height.max(1) as f32)
+        .min(MAX_UPSCALE);
+
+    (
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+    )
+}
+
+type Decoded = ((u32, u32), u32, u32, Vec<u8>);
+
+fn decode_raster(data: &[u8], max_width: u32, max_height: u32) -> Result<Decoded> {
+    // Only the first frame of animated GIFs/WebPs is shown.
+    let image = image::load_from_memory(data).map_err(Error::render)?;
+    let original = (image.width(), image.height());
+    let (width, height) = fit(original.0, original.1, max_width, max_height);
+    let rgba = image
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_rgba8();
+
+    Ok((original, width, height, rgba.into_raw()))
+}
+
+fn decode_svg(data: &[u8], max_width: u32, max_height: u32) -> Result<Decoded> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).map_err(Error::render)?;
+    let size = tree.size();
+    let original = (size.width().ceil() as u32, size.height().ceil() as u32);
+    let (width, height) = fit(original.0, original.1, max_width, max_height);
+
+    Ok((original, width, height, render_svg(&tree, width, height)?))
+}
+
+/// Renders an SVG stretched to `width` x `height`, as straight-alpha RGBA.
+fn render_svg(tree: &usvg::Tree, width: u32, height: u32) -> Result<Vec<u8>> {
+    let size = tree.size();
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| Error::Render("SVG has no area".into()))?;
+    resvg::render(
+        tree,
+        tiny_skia::Transform::from_scale(
+            width as f32 / size.width(),
+            height as f32 / size.height(),
+        ),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia keeps premultiplied alpha, `dither` expects straight alpha.
+    Ok(pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect())
+}
+
+fn draw_overlay(svg: &[u8], width: u32, height: u32, rgba: &mut [u8]) -> Result<()> {
+    let tree = usvg::Tree::from_data(svg, &usvg::Options::default()).map_err(Error::render)?;
+    let overlay = render_svg(&tree, width, height)?;
+
+    for (base, top) in rgba.chunks_exact_mut(4).zip(overlay.chunks_exact(4)) {
+        let alpha = top[3] as f32 / 255.0;
+        for (b, t) in base[..3].iter_mut().zip(&top[..3]) {
+            *b = (*t as f32 * alpha + *b as f32 * (1.0 - alpha)).round() as u8;
+        }
+        base[3] = base[3].max(top[3]);
+    }
+
+    Ok(())
+}
+
+/// Flattens RGBA onto white, converts to luma and applies Floyd-Steinberg
+/// dithering down to the panel's gray levels. Returns packed RGB.
+fn dither(rgba: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+
+    let mut luma: Vec<f32> = rgba
+        .chunks_exact(4)
+        .map(|p| {
+            let alpha = p[3] as f32 / 255.0;
+            let value = 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+            value * alpha + 255.0 * (1.0 - alpha)
+        })
+        .collect();
+
+    let step = 255.0 / (GRAY_LEVELS - 1) as f32;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let old = luma[i].clamp(0.0, 255.0);
+            let new = (old / step).round() * step;
+            let error = old - new;
+
+            if x + 1 < width {
+                luma[i + 1] += error * 7.0 / 16.0;
+            }
+            if y + 1 < height {
+                if x > 0 {
+                    luma[i + width - 1] += error * 3.0 / 16.0;
+                }
+                luma[i + width] += error * 5.0 / 16.0;
+                if x + 1 < width {
+                    luma[i + width + 1] += error * 1.0 / 16.0;
+                }
+            }
+
+            let value = new as u8;
+            rgb.extend_from_slice(&[value, value, value]);
+        }
+    }
+
+    rgb
+}
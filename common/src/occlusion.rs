@@ -0,0 +1,293 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use anki::notes::Note;
+use anki::notetype::Notetype;
+use anki_proto::notetypes::stock_notetype::OriginalStockKind;
+use regex::Regex;
+
+use crate::utils::HtmlSegment;
+
+/// Notetype of the Image Occlusion Enhanced add-on.
+const LEGACY_NOTETYPE: &str = "Image Occlusion Enhanced";
+
+/// Fields of the add-on shown below the image on the answer side.
+const LEGACY_EXTRA_FIELDS: &[&str] = &["Footer", "Remarks", "Sources", "Extra 1", "Extra 2"];
+
+static OCCLUSION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{c(?P<ord>\d+)::image-occlusion:(?P<shape>[a-z]+)(?P<props>[^}]*)\}\}").unwrap()
+});
+
+static NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"-?\d*\.?\d+").unwrap());
+
+/// An image occlusion note, from either Anki's built-in notetype or the
+/// legacy add-on.
+pub struct OcclusionNote {
+    /// `src` of the image the masks are drawn over.
+    pub image: String,
+    pub masks: Masks,
+    /// Shown above the image on both sides.
+    pub header: String,
+    /// Shown below the image on the answer side.
+    pub extra: String,
+}
+
+pub enum Masks {
+    /// The add-on stores rendered SVG masks in the media folder, one with
+    /// every mask and one with the card's mask removed.
+    Files { question: String, answer: String },
+    /// The built-in notetype keeps its shapes as clozes of the Occlusion field.
+    Shapes(Vec<Occlusion>),
+}
+
+pub struct Occlusion {
+    pub ordinal: u16,
+    pub shape: Shape,
+    /// Hidden on other cards of the note too ("Hide All, Guess One").
+    pub occlude_inactive: bool,
+}
+
+/// Coordinates are fractions of the image size, or pixels in notes created
+/// by Anki 23.10.
+pub enum Shape {
+    Rect {
+        left: f32,
+        top: f32,
+        width: f32,
+        height: f32,
+    },
+    Ellipse {
+        left: f32,
+        top: f32,
+        rx: f32,
+        ry: f32,
+    },
+    Polygon(Vec<(f32, f32)>),
+}
+
+impl OcclusionNote {
+    pub fn from_note(notetype: &Notetype, note: &Note) -> Option<Self> {
+        let fields = note.fields();
+
+        if notetype.config.original_stock_kind == OriginalStockKind::ImageOcclusion as i32 {
+            // Field order of the stock notetype, the names are translated.
+            let field = |index: usize| fields.get(index).cloned().unwrap_or_default();
+            return Some(OcclusionNote {
+                image: image_src(&field(1))?,
+                masks: Masks::Shapes(parse_occlusions(&field(0))),
+                header: field(2),
+                extra: field(3),
+            });
+        }
+
+        if notetype.name == LEGACY_NOTETYPE {
+            let field = |name: &str| {
+                notetype
+                    .fields
+                    .iter()
+                    .position(|f| f.name == name)
+                    .and_then(|index| fields.get(index))
+                    .map_or("", String::as_str)
+            };
+            let extra = LEGACY_EXTRA_FIELDS
+                .iter()
+                .map(|name| field(name))
+                .filter(|value| !value.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join("<br>");
+
+            return Some(OcclusionNote {
+                image: image_src(field("Image"))?,
+                masks: Masks::Files {
+                    question: image_src(field("Question Mask"))?,
+                    answer: image_src(field("Answer Mask"))?,
+                },
+                header: field("Header").to_string(),
+                extra,
+            });
+        }
+
+        None
+    }
+
+    /// The SVG drawn over the image for cloze `ordinal`, with the active mask
+    /// removed if `reveal` is set. `width` and `height` are the image's size.
+    pub fn overlay(
+        &self,
+        media_folder: &Path,
+        ordinal: u16,
+        reveal: bool,
+        width: u32,
+        height: u32,
+    ) -> Option<Vec<u8>> {
+        match &self.masks {
+            Masks::Files { question, answer } => {
+                let mask = if reveal { answer } else { question };
+                fs::read(crate::media::resolve(media_folder, mask)?).ok()
+            }
+            Masks::Shapes(occlusions) => {
+                Some(masks_svg(occlusions, ordinal, reveal, width, height).into_bytes())
+            }
+        }
+    }
+}
+
+fn image_src(html: &str) -> Option<String> {
+    crate::utils::split_images(html)
+        .into_iter()
+        .find_map(|segment| match segment {
+            HtmlSegment::Image(tag) => Some(tag.src),
+            HtmlSegment::Html(_) => None,
+        })
+}
+
+/// Parses the `{{c1::image-occlusion:rect:left=.1:top=.2:...}}` clozes of
+/// the Occlusion field. Text labels are skipped.
+pub fn parse_occlusions(field: &str) -> Vec<Occlusion> {
+    let mut occlusions = Vec::new();
+
+    for caps in OCCLUSION.captures_iter(field) {
+        let Ok(ordinal) = caps["ord"].parse() else {
+            continue;
+        };
+        let props: Vec<(&str, &str)> = caps["props"]
+            .split(':')
+            .filter_map(|prop| prop.split_once('='))
+            .collect();
+        let prop = |name: &str| {
+            props
+                .iter()
+                .find(|(key, _)| *key == name)
+                .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+                .unwrap_or(0.0)
+        };
+
+        let shape = match &caps["shape"] {
+            "rect" => Shape::Rect {
+                left: prop("left"),
+                top: prop("top"),
+                width: prop("width"),
+                height: prop("height"),
+            },
+            "ellipse" => Shape::Ellipse {
+                left: prop("left"),
+                top: prop("top"),
+                rx: prop("rx"),
+                ry: prop("ry"),
+            },
+            "polygon" => {
+                let points = props
+                    .iter()
+                    .find(|(key, _)| *key == "points")
+                    .map_or("", |(_, value)| *value);
+                let numbers: Vec<f32> = NUMBER
+                    .find_iter(points)
+                    .filter_map(|n| n.as_str().parse().ok())
+                    .collect();
+                Shape::Polygon(numbers.chunks_exact(2).map(|p| (p[0], p[1])).collect())
+            }
+            _ => continue,
+        };
+
+        occlusions.push(Occlusion {
+            ordinal,
+            shape,
+            occlude_inactive: prop("oi") == 1.0,
+        });
+    }
+
+    occlusions
+}
+
+impl Shape {
+    fn coordinates(&self) -> Vec<f32> {
+        match self {
+            Shape::Rect {
+                left,
+                top,
+                width,
+                height,
+            } => vec![*left, *top, *width, *height],
+            Shape::Ellipse { left, top, rx, ry } => vec![*left, *top, *rx, *ry],
+            Shape::Polygon(points) => points.iter().flat_map(|&(x, y)| [x, y]).collect(),
+        }
+    }
+}
+
+/// Draws the masks in grays the panel shows well: the active mask dark, the
+/// others light, and only the outline of the active one once revealed.
+fn masks_svg(
+    occlusions: &[Occlusion],
+    ordinal: u16,
+    reveal: bool,
+    width: u32,
+    height: u32,
+) -> String {
+    let normalized = occlusions
+        .iter()
+        .flat_map(|o| o.shape.coordinates())
+        .all(|c| c <= 1.0);
+    let (sx, sy) = if normalized {
+        (width as f32, height as f32)
+    } else {
+        (1.0, 1.0)
+    };
+    let stroke = (width.max(height) as f32 / 300.0).max(1.0);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+
+    for occlusion in occlusions {
+        let active = occlusion.ordinal == ordinal;
+        let style = match (active, reveal) {
+            (true, true) => format!(
+                r#"fill="none" stroke="black" stroke-width="{}""#,
+                stroke * 2.0
+            ),
+            (true, false) => format!(r##"fill="#404040" stroke="black" stroke-width="{stroke}""##),
+            (false, _) if occlusion.occlude_inactive => {
+                format!(r##"fill="#C8C8C8" stroke="black" stroke-width="{stroke}""##)
+            }
+            (false, _) => continue,
+        };
+
+        let _ = match &occlusion.shape {
+            Shape::Rect {
+                left,
+                top,
+                width,
+                height,
+            } => write!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" {style}/>"#,
+                left * sx,
+                top * sy,
+                width * sx,
+                height * sy
+            ),
+            Shape::Ellipse { left, top, rx, ry } => write!(
+                svg,
+                r#"<ellipse cx="{}" cy="{}" rx="{}" ry="{}" {style}/>"#,
+                (left + rx) * sx,
+                (top + ry) * sy,
+                rx * sx,
+                ry * sy
+            ),
+            Shape::Polygon(points) => write!(
+                svg,
+                r#"<polygon points="{}" {style}/>"#,
+                points
+                    .iter()
+                    .map(|(x, y)| format!("{},{}", x * sx, y * sy))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        };
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use anki::scheduler::states::{CardState, FilteredState, NormalState};
+
+/// Which queue an answered card came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardKind {
+    New,
+    /// Learning and relearning cards.
+    Learning,
+    Review,
+}
+
+impl CardKind {
+    pub fn of(state: &CardState) -> Self {
+        let normal = match state {
+            CardState::Normal(normal) => normal,
+            CardState::Filtered(FilteredState::Rescheduling(filtered)) => &filtered.original_state,
+            // Cards previewed in a filtered deck step through it like learning.
+            CardState::Filtered(FilteredState::Preview(_)) => return CardKind::Learning,
+        };
+
+        match normal {
+            NormalState::New(_) => CardKind::New,
+            NormalState::Learning(_) | NormalState::Relearning(_) => CardKind::Learning,
+            NormalState::Review(_) => CardKind::Review,
+        }
+    }
+}
+
+struct Answer {
+    /// 0 for Again to 3 for Easy.
+    rating: usize,
+    kind: CardKind,
+    taken: Duration,
+}
+
+/// What has been studied since a deck was opened, and Anki's "timebox": after
+/// the collection's time limit the user is told how much they got done.
+pub struct SessionStats {
+    answers: Vec<Answer>,
+    /// Zero when the collection has no time limit.
+    time_limit: Duration,
+    timebox_start: Instant,
+    timebox_answers: usize,
+}
+
+impl SessionStats {
+    pub fn new(time_limit: Duration) -> Self {
+        SessionStats {
+            answers: Vec::new(),
+            time_limit,
+            timebox_start: Instant::now(),
+            timebox_answers: 0,
+        }
+    }
+
+    pub fn record(&mut self, rating: usize, kind: CardKind, taken: Duration) {
+        self.answers.push(Answer {
+            rating,
+            kind,
+            taken,
+        });
+        self.timebox_answers += 1;
+    }
+
+    /// Forgets the last answer after it was undone.
+    pub fn undo(&mut self) {
+        if self.answers.pop().is_some() {
+            self.timebox_answers = self.timebox_answers.saturating_sub(1);
+        }
+    }
+
+    pub fn answered(&self) -> usize {
+        self.answers.len()
+    }
+
+    pub fn rating_count(&self, rating: usize) -> usize {
+        self.answers.iter().filter(|a| a.rating == rating).count()
+    }
+
+    pub fn kind_count(&self, kind: CardKind) -> usize {
+        self.answers.iter().filter(|a| a.kind == kind).count()
+    }
+
+    /// Time spent looking at the cards that were answered.
+    pub fn time_taken(&self) -> Duration {
+        self.answers.iter().map(|a| a.taken).sum()
+    }
+
+    /// Once the time limit has run out, the time it was and the cards
+    /// answered in it. The next timebox starts from here.
+    pub fn take_timebox(&mut self) -> Option<(Duration, usize)> {
+        if self.time_limit.is_zero() || self.timebox_start.elapsed() < self.time_limit {
+            return None;
+        }
+
+        let answers = self.timebox_answers;
+        self.timebox_start = Instant::now();
+        self.timebox_answers = 0;
+        Some((self.time_limit, answers))
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        SessionStats::new(Duration::ZERO)
+    }
+}
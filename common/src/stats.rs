@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anki::prelude::I18n;
+use anki_proto::stats::GraphsResponse;
+use anki_proto::stats::graphs_response::card_counts::Counts;
+use anki_proto::stats::graphs_response::true_retention_stats::TrueRetention;
+
+use crate::{Chart, ChartBar, Stats, StatsRow};
+
+/// Weeks of reviews in the heatmap, a column each.
+pub const HEATMAP_WEEKS: u32 = 26;
+/// Days ahead in the future due forecast.
+const FUTURE_DAYS: i32 = 30;
+/// Shades of grey in the heatmap above an empty day, see stats.slint.
+const HEATMAP_LEVELS: u32 = 4;
+
+/// Anki's "20 cards studied in 10 minutes.", as after a timebox.
+pub fn studied_in(tr: &I18n, cards: usize, time: Duration) -> String {
+    let minutes = (time.as_secs_f64() / 60.0).round();
+    format!(
+        "{} {}",
+        tr.studying_card_studied_in(cards as u32),
+        tr.studying_minute(minutes)
+    )
+}
+
+/// Turns the collection's graph data into the charts of the stats screen.
+/// `scope` names the deck or the collection the data is for.
+pub fn stats(graphs: &GraphsResponse, tr: &I18n, scope: String) -> Stats {
+    let today = graphs.today.as_ref().cloned().unwrap_or_default();
+    let counts = graphs
+        .card_counts
+        .as_ref()
+        .and_then(|counts| counts.excluding_inactive.as_ref())
+        .cloned()
+        .unwrap_or_default();
+    let reviews = graphs
+        .reviews
+        .as_ref()
+        .map(|reviews| {
+            reviews
+                .count
+                .iter()
+                .map(|(&day, count)| {
+                    let total =
+                        count.learn + count.relearn + count.young + count.mature + count.filtered;
+                    (day, total)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Stats {
+        scope: scope.into(),
+        today: studied_in(
+            tr,
+            today.answer_count as usize,
+            Duration::from_millis(today.answer_millis as u64),
+        )
+        .into(),
+        today_rows: model(today_rows(&today, tr)),
+        future_due: future_due(graphs, tr),
+        heatmap: model(heatmap(&reviews)),
+        card_counts: model(card_counts(&counts, tr)),
+        true_retention: model(true_retention(graphs, tr)),
+        fsrs: graphs.fsrs,
+        retrievability: retrievability(graphs),
+    }
+}
+
+fn model<T: Clone + 'static>(items: Vec<T>) -> slint::ModelRc<T> {
+    Rc::new(slint::VecModel::from(items)).into()
+}
+
+fn row(label: impl AsRef<str>, value: String, share: f32) -> StatsRow {
+    StatsRow {
+        label: label.as_ref().into(),
+        value: value.into(),
+        share,
+    }
+}
+
+fn percent(part: u32, total: u32) -> String {
+    if total == 0 {
+        "-".into()
+    } else {
+        format!("{:.1}%", part as f32 * 100.0 / total as f32)
+    }
+}
+
+fn today_rows(today: &anki_proto::stats::graphs_response::Today, tr: &I18n) -> Vec<StatsRow> {
+    let total = today.answer_count.max(1) as f32;
+    let again = today.answer_count - today.correct_count.min(today.answer_count);
+    [
+        (tr.studying_again(), again),
+        (tr.statistics_counts_learning_cards(), today.learn_count),
+        (tr.scheduling_reviews(), today.review_count),
+        (tr.statistics_counts_relearning_cards(), today.relearn_count),
+    ]
+    .into_iter()
+    .map(|(label, count)| row(label, count.to_string(), count as f32 / total))
+    .collect()
+}
+
+/// Cards due each day from today on, cards that are overdue count for today.
+fn future_due(graphs: &GraphsResponse, tr: &I18n) -> Chart {
+    let mut days = vec![0; FUTURE_DAYS as usize];
+    if let Some(future_due) = &graphs.future_due {
+        for (&day, &count) in &future_due.future_due {
+            if day < FUTURE_DAYS {
+                days[day.max(0) as usize] += count;
+            }
+        }
+    }
+
+    bar_chart(
+        days.iter()
+            .enumerate()
+            .map(|(day, &count)| {
+                let label = if day % 7 == 0 {
+                    format!("+{}", day)
+                } else {
+                    String::new()
+                };
+                (count, label)
+            })
+            .collect(),
+        format!("{}: {}", tr.statistics_due_tomorrow(), days[1]),
+    )
+}
+
+/// Share of cards by how likely they are to be recalled now, in tenths.
+fn retrievability(graphs: &GraphsResponse) -> Chart {
+    let mut buckets = [0; 10];
+    let mut caption = String::new();
+    if let Some(retrievability) = &graphs.retrievability {
+        for (&percent, &count) in &retrievability.retrievability {
+            buckets[(percent / 10).min(9) as usize] += count;
+        }
+        caption = format!("⌀ {:.0}%", retrievability.average);
+    }
+
+    bar_chart(
+        buckets
+            .iter()
+            .enumerate()
+            .map(|(tenth, &count)| (count, format!("{}%", tenth * 10)))
+            .collect(),
+        caption,
+    )
+}
+
+fn bar_chart(bars: Vec<(u32, String)>, caption: String) -> Chart {
+    let max = bars
+        .iter()
+        .map(|(count, _)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    Chart {
+        max: max.to_string().into(),
+        caption: caption.into(),
+        bars: model(
+            bars.into_iter()
+                .map(|(count, label)| ChartBar {
+                    height: count as f32 / max as f32,
+                    label: label.into(),
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Review counts of the last [`HEATMAP_WEEKS`] weeks as shades from 0 to
+/// [`HEATMAP_LEVELS`], a week per column ending with today.
+fn heatmap(reviews: &HashMap<i32, u32>) -> Vec<i32> {
+    let days = HEATMAP_WEEKS as i32 * 7;
+    let counts: Vec<u32> = (1 - days..=0)
+        .map(|day| reviews.get(&day).copied().unwrap_or(0))
+        .collect();
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+
+    counts
+        .into_iter()
+        .map(|count| (count * HEATMAP_LEVELS).div_ceil(max) as i32)
+        .collect()
+}
+
+fn card_counts(counts: &Counts, tr: &I18n) -> Vec<StatsRow> {
+    let rows = [
+        (tr.statistics_counts_new_cards(), counts.new_cards),
+        (tr.statistics_counts_learning_cards(), counts.learn),
+        (tr.statistics_counts_relearning_cards(), counts.relearn),
+        (tr.statistics_counts_young_cards(), counts.young),
+        (tr.statistics_counts_mature_cards(), counts.mature),
+        (tr.statistics_counts_suspended_cards(), counts.suspended),
+        (tr.statistics_counts_buried_cards(), counts.buried),
+    ];
+    let total = rows.iter().map(|(_, count)| count).sum::<u32>().max(1) as f32;
+
+    rows.into_iter()
+        .map(|(label, count)| row(label, count.to_string(), count as f32 / total))
+        .collect()
+}
+
+/// Pass rate of reviews of cards with an interval of a day or more.
+fn true_retention(graphs: &GraphsResponse, tr: &I18n) -> Vec<StatsRow> {
+    let Some(retention) = &graphs.true_retention else {
+        return Vec::new();
+    };
+
+    [
+        (tr.statistics_true_retention_today(), &retention.today),
+        (
+            tr.statistics_true_retention_yesterday(),
+            &retention.yesterday,
+        ),
+        (tr.statistics_true_retention_week(), &retention.week),
+        (tr.statistics_true_retention_month(), &retention.month),
+        (tr.statistics_true_retention_year(), &retention.year),
+        (tr.statistics_true_retention_all_time(), &retention.all_time),
+    ]
+    .into_iter()
+    .map(|(label, period)| {
+        let TrueRetention {
+            young_passed,
+            young_failed,
+            mature_passed,
+            mature_failed,
+        } = period.as_ref().cloned().unwrap_or_default();
+        let passed = young_passed + mature_passed;
+        let total = passed + young_failed + mature_failed;
+        let value = format!("{} ({}/{})", percent(passed, total), passed, total);
+        row(label, value, passed as f32 / total.max(1) as f32)
+    })
+    .collect()
+}
@@ -1,8 +1,15 @@
 use anki::collection::Collection;
-use anki::sync::collection::normal::SyncOutput;
-use anki::sync::login::{sync_login, SyncAuth};
+use anki::error::{AnkiError, SyncErrorKind};
+use anki::prelude::I18n;
+use anki::progress::{Progress, ProgressState};
+use anki::sync::collection::normal::{SyncActionRequired, SyncOutput};
+use anki::sync::login::{SyncAuth, sync_login};
+use anki::sync::media::progress::MediaSyncProgress;
+use anki::types::Usn;
 use anki_proto::sync::sync_status_response;
+use std::future::Future;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum SyncStatus {
@@ -30,11 +37,84 @@ pub struct SyncResult {
     pub success: bool,
     pub message: String,
     pub server_message: Option<String>,
+    /// Media USN reported by the server during a normal sync, used to skip
+    /// the media sync round trip when nothing changed on the server.
+    pub server_media_usn: Option<Usn>,
+    /// Set when the collections diverged and the user has to pick a
+    /// direction for a full sync.
+    pub full_sync_required: bool,
+    /// Set when there is no usable token and the user has to log in.
+    pub login_required: bool,
+    /// The server AnkiWeb moved the account to, to be saved in the config.
+    pub new_endpoint: Option<String>,
 }
 
+impl SyncStatus {
+    pub fn message(&self) -> String {
+        match self {
+            SyncStatus::Idle => String::new(),
+            SyncStatus::Authenticating => "Logging in...".into(),
+            SyncStatus::CheckingStatus => "Checking sync status...".into(),
+            SyncStatus::SyncingCollection { progress } => progress.clone(),
+            SyncStatus::SyncingMedia {
+                checked,
+                added,
+                removed,
+            } => {
+                let parts: Vec<&str> = [checked, added, removed]
+                    .into_iter()
+                    .map(String::as_str)
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                if parts.is_empty() {
+                    "Syncing media...".into()
+                } else {
+                    parts.join(", ")
+                }
+            }
+            SyncStatus::Complete { message } | SyncStatus::Error { message } => message.clone(),
+        }
+    }
+}
+
+impl SyncResult {
+    pub fn failure(message: String) -> Self {
+        Self {
+            success: false,
+            message,
+            server_message: None,
+            server_media_usn: None,
+            full_sync_required: false,
+            login_required: false,
+            new_endpoint: None,
+        }
+    }
+
+    pub fn login_required(message: String) -> Self {
+        Self {
+            login_required: true,
+            ..Self::failure(message)
+        }
+    }
+}
+
+pub const CREDENTIALS_INVALID: &str = "AnkiWeb credentials invalid. Please log in again.";
+
+type StatusListener = Box<dyn Fn(&SyncStatus) + Send>;
+
 pub struct SyncManager {
     http_client: reqwest::Client,
     sync_status: Arc<Mutex<SyncStatus>>,
+    listener: Mutex<Option<StatusListener>>,
+    /// Token from the last successful login, so that later syncs in this
+    /// session don't depend on the copy of the config they were started with.
+    hkey: Mutex<Option<String>>,
+    /// A token the server refused, so a stale copy in the config isn't
+    /// offered again before the user logs in.
+    rejected_hkey: Mutex<Option<String>>,
+    progress_state: Arc<Mutex<ProgressState>>,
+    /// Replaced when AnkiWeb redirects the account to another server.
+    endpoint: Mutex<Option<String>>,
 }
 
 impl SyncManager {
@@ -45,37 +125,115 @@ impl SyncManager {
                 .build()
                 .unwrap(),
             sync_status: Arc::new(Mutex::new(SyncStatus::Idle)),
+            listener: Mutex::new(None),
+            hkey: Mutex::new(None),
+            rejected_hkey: Mutex::new(None),
+            progress_state: Arc::new(Mutex::new(ProgressState {
+                want_abort: false,
+                last_progress: None,
+            })),
+            endpoint: Mutex::new(None),
         }
     }
 
+    /// Points the manager at a self-hosted sync server instead of AnkiWeb.
+    /// Anki finds the media sync under the same base URL.
+    pub fn with_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.endpoint = Mutex::new(endpoint.filter(|e| !e.trim().is_empty()));
+        self
+    }
+
+    /// The server syncs go to, `None` for AnkiWeb's default.
+    pub fn endpoint(&self) -> Option<String> {
+        self.endpoint.lock().unwrap().clone()
+    }
+
+    /// Progress state the collection has to be opened with, so that anki's
+    /// progress handlers report into something the manager can poll.
+    pub fn progress_state(&self) -> Arc<Mutex<ProgressState>> {
+        self.progress_state.clone()
+    }
+
     pub fn get_status(&self) -> SyncStatus {
         self.sync_status.lock().unwrap().clone()
     }
 
+    /// Registers a callback that is invoked on every status transition, from
+    /// whichever thread is running the sync.
+    pub fn set_listener(&self, listener: Option<StatusListener>) {
+        *self.listener.lock().unwrap() = listener;
+    }
+
+    fn set_status(&self, status: SyncStatus) {
+        if let Some(listener) = self.listener.lock().unwrap().as_ref() {
+            listener(&status);
+        }
+        *self.sync_status.lock().unwrap() = status;
+    }
+
     pub async fn login(
         &self,
         username: &str,
         password: &str,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        *self.sync_status.lock().unwrap() = SyncStatus::Authenticating;
+        self.set_status(SyncStatus::Authenticating);
 
-        let auth = sync_login(username, password, None, self.http_client.clone()).await?;
+        let auth = match sync_login(
+            username,
+            password,
+            self.endpoint().as_deref().map(normalize_endpoint),
+            self.http_client.clone(),
+        )
+        .await
+        {
+            Ok(auth) => auth,
+            Err(e) => {
+                let message = if is_auth_error(&e) {
+                    CREDENTIALS_INVALID.to_string()
+                } else {
+                    format!("Login failed: {}", e)
+                };
+                self.set_status(SyncStatus::Error { message });
+                return Err(e.into());
+            }
+        };
 
+        *self.hkey.lock().unwrap() = Some(auth.hkey.clone());
         Ok(auth.hkey)
     }
 
+    pub fn hkey(&self) -> Option<String> {
+        self.hkey.lock().unwrap().clone()
+    }
+
+    /// Forgets the token of the last login, after the server rejected it.
+    pub fn clear_hkey(&self) {
+        *self.hkey.lock().unwrap() = None;
+    }
+
+    /// Forgets `hkey` and keeps it from being used again in this session.
+    pub fn reject_hkey(&self, hkey: &str) {
+        self.clear_hkey();
+        *self.rejected_hkey.lock().unwrap() = Some(hkey.to_string());
+    }
+
+    pub fn is_rejected(&self, hkey: &str) -> bool {
+        self.rejected_hkey.lock().unwrap().as_deref() == Some(hkey)
+    }
+
+    /// Whether `err` means the server rejected our credentials or token.
+    pub fn is_auth_error(err: &(dyn std::error::Error + 'static)) -> bool {
+        err.downcast_ref::<AnkiError>().is_some_and(is_auth_error)
+    }
+
     pub async fn sync_collection(
         &self,
         collection: &mut Collection,
         hkey: &str,
     ) -> Result<SyncResult, Box<dyn std::error::Error>> {
-        let auth = SyncAuth {
-            hkey: hkey.to_string(),
-            endpoint: None,
-            io_timeout_secs: Some(60),
-        };
+        let auth = self.auth(hkey, self.endpoint().as_deref())?;
 
-        *self.sync_status.lock().unwrap() = SyncStatus::CheckingStatus;
+        self.set_status(SyncStatus::CheckingStatus);
 
         let sync_required = collection.sync_status_offline()?;
 
@@ -83,58 +241,252 @@ impl SyncManager {
             success: true,
             message: String::new(),
             server_message: None,
+            server_media_usn: None,
+            full_sync_required: false,
+            login_required: false,
+            new_endpoint: None,
         };
 
         match sync_required {
             sync_status_response::Required::NoChanges => {
                 result.message = "No changes to sync".into();
-                *self.sync_status.lock().unwrap() = SyncStatus::Complete {
+                self.set_status(SyncStatus::Complete {
                     message: result.message.clone(),
-                };
+                });
             }
             sync_status_response::Required::NormalSync => {
-                *self.sync_status.lock().unwrap() = SyncStatus::SyncingCollection {
+                self.set_status(SyncStatus::SyncingCollection {
                     progress: "Syncing collection...".into(),
-                };
+                });
 
                 let sync_output: SyncOutput = collection
                     .normal_sync(auth.clone(), self.http_client.clone())
                     .await?;
 
-                result.message = "Collection synced successfully".into();
+                // Later requests, media and full syncs included, have to go
+                // to the server the account was moved to.
+                if let Some(endpoint) = sync_output.new_endpoint.filter(|e| !e.is_empty()) {
+                    *self.endpoint.lock().unwrap() = Some(endpoint.clone());
+                    result.new_endpoint = Some(endpoint);
+                }
+
                 result.server_message = Some(sync_output.server_message);
+                result.server_media_usn = Some(sync_output.server_media_usn);
 
-                *self.sync_status.lock().unwrap() = SyncStatus::Complete {
-                    message: result.message.clone(),
-                };
+                // The server may only notice a schema mismatch once we talk to it.
+                if let SyncActionRequired::FullSyncRequired { .. } = sync_output.required {
+                    self.require_full_sync(&mut result);
+                } else {
+                    result.message = "Collection synced successfully".into();
+                    self.set_status(SyncStatus::Complete {
+                        message: result.message.clone(),
+                    });
+                }
             }
             sync_status_response::Required::FullSync => {
-                result.success = false;
-                result.message = "Full sync required. Please sync via desktop Anki first.".into();
-                *self.sync_status.lock().unwrap() = SyncStatus::Error {
-                    message: result.message.clone(),
-                };
+                self.require_full_sync(&mut result);
             }
         }
 
         Ok(result)
     }
 
+    fn require_full_sync(&self, result: &mut SyncResult) {
+        result.success = false;
+        result.full_sync_required = true;
+        result.message =
+            "Full sync required. Choose whether to upload to or download from AnkiWeb.".into();
+        self.set_status(SyncStatus::Error {
+            message: result.message.clone(),
+        });
+    }
+
     pub async fn sync_media(
         &self,
-        _collection: &mut Collection,
-        _hkey: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        Err("Media sync not yet implemented - requires progress handler refactoring".into())
+        collection: &mut Collection,
+        hkey: &str,
+        server_usn: Option<Usn>,
+    ) -> Result<SyncResult, Box<dyn std::error::Error>> {
+        let auth = self.auth(hkey, self.endpoint().as_deref())?;
+
+        self.set_status(SyncStatus::SyncingMedia {
+            checked: String::new(),
+            added: String::new(),
+            removed: String::new(),
+        });
+
+        let tr = collection.tr().clone();
+        let media = collection.media()?;
+        let progress = collection.new_progress_handler::<MediaSyncProgress>();
+
+        let outcome = self
+            .with_progress(
+                media.sync_media(progress, auth, self.http_client.clone(), server_usn),
+                &tr,
+            )
+            .await;
+
+        if let Err(e) = outcome {
+            self.set_status(SyncStatus::Error {
+                message: format!("Media sync failed: {}", e),
+            });
+            return Err(e.into());
+        }
+
+        let result = SyncResult {
+            success: true,
+            message: "Media synced successfully".into(),
+            server_message: None,
+            server_media_usn: server_usn,
+            full_sync_required: false,
+            login_required: false,
+            new_endpoint: None,
+        };
+        self.set_status(SyncStatus::Complete {
+            message: result.message.clone(),
+        });
+
+        Ok(result)
     }
 
+    /// Replaces one side with the other. The collection is consumed and
+    /// closed by anki in the process, so the caller has to reopen it from
+    /// disk afterwards, whether the sync succeeded or not.
     pub async fn full_sync(
         &self,
-        _collection: &mut Collection,
-        _hkey: &str,
-        _upload: bool,
+        collection: Collection,
+        hkey: &str,
+        upload: bool,
     ) -> Result<SyncResult, Box<dyn std::error::Error>> {
-        Err("Full sync not yet implemented - requires Collection ownership transfer".into())
+        let auth = self.auth(hkey, self.endpoint().as_deref())?;
+        let tr = collection.tr().clone();
+
+        self.set_status(SyncStatus::SyncingCollection {
+            progress: if upload {
+                tr.sync_uploading_to_ankiweb().into()
+            } else {
+                tr.sync_downloading_from_ankiweb().into()
+            },
+        });
+
+        let outcome = if upload {
+            self.with_progress(collection.full_upload(auth, self.http_client.clone()), &tr)
+                .await
+        } else {
+            self.with_progress(
+                collection.full_download(auth, self.http_client.clone()),
+                &tr,
+            )
+            .await
+        };
+
+        if let Err(e) = outcome {
+            self.set_status(SyncStatus::Error {
+                message: format!("Full sync failed: {}", e),
+            });
+            return Err(e.into());
+        }
+
+        let result = SyncResult {
+            success: true,
+            message: if upload {
+                "Collection uploaded to AnkiWeb".into()
+            } else {
+                "Collection downloaded from AnkiWeb".into()
+            },
+            server_message: None,
+            server_media_usn: None,
+            full_sync_required: false,
+            login_required: false,
+            new_endpoint: None,
+        };
+        self.set_status(SyncStatus::Complete {
+            message: result.message.clone(),
+        });
+
+        Ok(result)
+    }
+
+    fn auth(
+        &self,
+        hkey: &str,
+        endpoint: Option<&str>,
+    ) -> Result<SyncAuth, Box<dyn std::error::Error>> {
+        let endpoint = match endpoint {
+            Some(endpoint) => Some(
+                reqwest::Url::parse(&normalize_endpoint(endpoint))
+                    .map_err(|e| format!("Invalid sync endpoint {:?}: {}", endpoint, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(SyncAuth {
+            hkey: hkey.to_string(),
+            endpoint,
+            io_timeout_secs: Some(60),
+        })
+    }
+
+    /// Drives `fut` to completion while mirroring anki's progress reports
+    /// into the sync status. Anki only writes into the shared progress
+    /// state, so it has to be polled while the operation is running.
+    async fn with_progress<F: Future>(&self, fut: F, tr: &I18n) -> F::Output {
+        self.progress_state.lock().unwrap().last_progress = None;
+
+        tokio::pin!(fut);
+        let mut ticker = tokio::time::interval(Duration::from_millis(250));
+        let output = loop {
+            tokio::select! {
+                res = &mut fut => break res,
+                _ = ticker.tick() => self.publish_progress(tr),
+            }
+        };
+        self.publish_progress(tr);
+
+        output
+    }
+
+    fn publish_progress(&self, tr: &I18n) {
+        let state = self.progress_state.lock().unwrap();
+        let status = match &state.last_progress {
+            Some(Progress::MediaSync(p)) => SyncStatus::SyncingMedia {
+                checked: tr.sync_media_checked_count(p.checked).into(),
+                added: tr
+                    .sync_media_added_count(p.uploaded_files, p.downloaded_files)
+                    .into(),
+                removed: tr
+                    .sync_media_removed_count(p.uploaded_deletions, p.downloaded_deletions)
+                    .into(),
+            },
+            Some(Progress::FullSync(p)) => SyncStatus::SyncingCollection {
+                progress: format!(
+                    "{} / {} KiB",
+                    p.transferred_bytes / 1024,
+                    p.total_bytes / 1024
+                ),
+            },
+            _ => return,
+        };
+        drop(state);
+        self.set_status(status);
+    }
+}
+
+fn is_auth_error(err: &AnkiError) -> bool {
+    matches!(
+        err,
+        AnkiError::SyncError { source } if matches!(source.kind, SyncErrorKind::AuthFailed)
+    )
+}
+
+/// Anki joins request paths onto the endpoint, which drops the last path
+/// segment unless the URL ends with a slash.
+fn normalize_endpoint(endpoint: &str) -> String {
+    let endpoint = endpoint.trim();
+    if endpoint.ends_with('/') {
+        endpoint.to_string()
+    } else {
+        format!("{}/", endpoint)
     }
 }
 
@@ -0,0 +1,54 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+static TYPE_MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[\[type:(?P<spec>[^\]]+)\]\]").unwrap());
+
+/// A `[[type:...]]` marker from a card template.
+pub struct TypeField {
+    /// The marker as it appears in the rendered card.
+    pub marker: String,
+    pub field: String,
+    /// Compare combining characters separately, off with `nc:`.
+    pub combining: bool,
+    /// Only the card's cloze is expected, set with `cloze:`.
+    pub cloze: bool,
+}
+
+/// Finds the type-in marker in rendered card HTML, the field can have any name.
+pub fn find(html: &str) -> Option<TypeField> {
+    let caps = TYPE_MARKER.captures(html)?;
+    let mut spec = caps["spec"].trim();
+    let mut combining = true;
+    let mut cloze = false;
+
+    loop {
+        if let Some(rest) = spec.strip_prefix("nc:") {
+            combining = false;
+            spec = rest;
+        } else if let Some(rest) = spec.strip_prefix("cloze:") {
+            cloze = true;
+            spec = rest;
+        } else {
+            break;
+        }
+    }
+
+    Some(TypeField {
+        marker: caps[0].to_string(),
+        field: spec.to_string(),
+        combining,
+        cloze,
+    })
+}
+
+/// The text the user has to type, taken from the field as Anki's reviewer
+/// does. For clozes only the deletions with the card's ordinal count. The
+/// markup is left for `anki::typeanswer::compare_answer` to strip.
+pub fn expected(field_html: &str, cloze_ord: Option<u16>) -> String {
+    match cloze_ord {
+        Some(ord) => anki::cloze::extract_cloze_for_typing(field_html, ord).into_owned(),
+        None => field_html.to_string(),
+    }
+}
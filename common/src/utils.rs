@@ -1,10 +1,15 @@
 use std::rc::Rc;
+use std::sync::LazyLock;
 
 use anki_proto::decks::DeckTreeNode;
+use regex::Regex;
 
 use slint::Model;
+use unicode_linebreak::BreakOpportunity;
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::DeckNode;
+use crate::fonts::TextMeasure;
+use crate::{DeckNode, PageLayout, TextLine, TextRun};
 
 pub fn flatten_tree(node: &DeckTreeNode) -> Rc<slint::VecModel<DeckNode>> {
     let result = Rc::new(slint::VecModel::<DeckNode>::default());
@@ -30,147 +35,1018 @@ fn flatten_tree_recursive(
             due: child.review_count as i32,
             has_children: !child.children.is_empty(),
             parent_index,
+            filtered: child.filtered,
         });
         flatten_tree_recursive(child, current_index, result);
     }
 }
 
-pub fn paginate_text(text: &str, chars_per_page: usize) -> Vec<slint::SharedString> {
-    if text.is_empty() {
-        return vec![slint::SharedString::new()];
+/// Padding per list nesting level in rich_text.slint, in content font sizes.
+const INDENT_EM: f32 = 1.2;
+
+/// `&shy;`, where long words may be broken. Shown as a hyphen only at the end
+/// of a line.
+const SOFT_HYPHEN: char = '\u{ad}';
+
+/// A paragraph, heading, list item, table row or preformatted section of a
+/// card, made up of styled runs.
+#[derive(Default)]
+pub struct RichBlock {
+    pub runs: Vec<TextRun>,
+    /// List nesting depth.
+    pub indent: usize,
+    /// Bullet or number of a list item.
+    pub marker: Option<String>,
+    /// `<pre>` keeps its whitespace and line breaks.
+    pub preformatted: bool,
+    /// A horizontal rule, without any runs.
+    pub rule: bool,
+}
+
+impl RichBlock {
+    pub fn plain(text: &str) -> Self {
+        RichBlock {
+            runs: vec![TextRun {
+                text: text.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
     }
+}
+
+static HTML_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<!--.*?-->|<(/?)([a-zA-Z][a-zA-Z0-9]*)\b([^>]*)>").unwrap());
+
+static DOUBLE_BRACKETS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)\[\[.*?\]\]").unwrap());
+
+const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "hr", "img", "input", "link", "meta", "source", "wbr",
+];
+
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "center",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// Removes `[[...]]` markers like `[[type:Back]]` from card HTML.
+pub fn remove_double_brackets(html: &str) -> std::borrow::Cow<'_, str> {
+    DOUBLE_BRACKETS.replace_all(html, "")
+}
+
+/// Converts card HTML into blocks of styled runs, keeping the formatting that
+/// the e-ink screen can show. Colors become highlights.
+pub fn html_to_blocks(html: &str) -> Vec<RichBlock> {
+    let mut builder = BlockBuilder::default();
+    let mut last = 0;
+    let mut skip_until: Option<String> = None;
 
-    let estimated_pages = (text.len() / chars_per_page).max(1);
-    let mut pages = Vec::with_capacity(estimated_pages + 1);
-    let mut current_page = String::with_capacity(chars_per_page);
-    let mut current_len = 0;
-    let mut first_word_in_page = true;
+    for caps in HTML_TAG.captures_iter(html) {
+        let tag = caps.get(0).unwrap();
+        let text = &html[last..tag.start()];
+        last = tag.end();
 
-    for word in text.split_whitespace() {
-        let word_len = word.chars().count();
-        let space_needed = if first_word_in_page { 0 } else { 1 };
+        let Some(name) = caps.get(2) else {
+            // A comment.
+            if skip_until.is_none() {
+                builder.push_text(text);
+            }
+            continue;
+        };
+        let name = name.as_str().to_ascii_lowercase();
+        let closing = !caps[1].is_empty();
 
-        if current_len + word_len + space_needed > chars_per_page && !current_page.is_empty() {
-            pages.push(current_page.as_str().into());
-            current_page.clear();
-            current_len = 0;
-            first_word_in_page = true;
+        if let Some(until) = &skip_until {
+            if closing && *until == name {
+                skip_until = None;
+            }
+            continue;
         }
+        builder.push_text(text);
 
-        if !first_word_in_page {
-            current_page.push(' ');
-            current_len += 1;
+        if !closing && matches!(name.as_str(), "script" | "style" | "head" | "title") {
+            skip_until = Some(name);
+            continue;
         }
-        current_page.push_str(word);
-        current_len += word_len;
-        first_word_in_page = false;
-    }
 
-    if !current_page.is_empty() {
-        pages.push(current_page.as_str().into());
+        let attrs = &caps[3];
+        if closing {
+            builder.close_tag(&name);
+        } else {
+            builder.open_tag(&name, attrs);
+            if VOID_TAGS.contains(&name.as_str()) || attrs.trim_end().ends_with('/') {
+                builder.close_tag(&name);
+            }
+        }
     }
 
-    if pages.is_empty() {
-        pages.push(slint::SharedString::new());
+    if skip_until.is_none() {
+        builder.push_text(&html[last..]);
     }
+    builder.finish()
+}
 
-    pages
+#[derive(Default)]
+struct BlockBuilder {
+    blocks: Vec<RichBlock>,
+    current: RichBlock,
+    /// Open elements and the style inside them.
+    open: Vec<(String, TextRun)>,
+    /// Next number of each open list, `None` for bulleted ones.
+    lists: Vec<Option<usize>>,
+    preformatted: usize,
+    pending_space: bool,
+    ruby: Option<Ruby>,
 }
 
-pub fn strip_html_remove_brackets_and_paginate(
-    html: &str,
-    remove_brackets: bool,
-    chars_per_page: usize,
-) -> Vec<slint::SharedString> {
-    let stripped = anki::text::strip_html(html);
-    let text = stripped.as_ref();
+/// A `<ruby>` element being read.
+#[derive(Default)]
+struct Ruby {
+    base: String,
+    reading: String,
+    in_reading: bool,
+    /// Inside `<rp>`, the parentheses for browsers without ruby support.
+    in_parenthesis: usize,
+}
 
-    if text.is_empty() {
-        return vec![slint::SharedString::new()];
+impl BlockBuilder {
+    fn style(&self) -> TextRun {
+        self.open
+            .last()
+            .map(|(_, style)| style.clone())
+            .unwrap_or_default()
     }
 
-    let estimated_pages = (text.len() / chars_per_page).max(1);
-    let mut pages = Vec::with_capacity(estimated_pages + 1);
-    let mut current_page = String::with_capacity(chars_per_page);
-    let mut current_len = 0;
-    let mut first_word_in_page = true;
+    fn break_block(&mut self) {
+        let next = RichBlock {
+            indent: self.lists.len(),
+            preformatted: self.preformatted > 0,
+            ..Default::default()
+        };
+        let current = std::mem::replace(&mut self.current, next);
+        if !current.runs.is_empty() {
+            self.blocks.push(current);
+        }
+        self.pending_space = false;
+    }
 
-    let mut chars = text.chars().peekable();
-    let mut word_buffer = String::with_capacity(128);
-    let mut skipping_brackets = false;
+    fn push_run(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
 
-    while let Some(c) = chars.next() {
-        if remove_brackets {
-            if !skipping_brackets {
-                if c == '[' {
-                    if let Some(&'[') = chars.peek() {
-                        chars.next();
-                        skipping_brackets = true;
-                        continue;
+        let style = self.style();
+        if let Some(last) = self.current.runs.last_mut()
+            && same_style(last, &style)
+        {
+            let mut merged = last.text.to_string();
+            merged.push_str(text);
+            last.text = merged.into();
+            return;
+        }
+
+        self.current.runs.push(TextRun {
+            text: text.into(),
+            ..style
+        });
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let text = anki::text::decode_entities(text);
+
+        if let Some(ruby) = &mut self.ruby {
+            if ruby.in_parenthesis == 0 {
+                let target = if ruby.in_reading {
+                    &mut ruby.reading
+                } else {
+                    &mut ruby.base
+                };
+                target.push_str(&text);
+            }
+            return;
+        }
+
+        if self.preformatted > 0 {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    // Blank lines are kept in preformatted text.
+                    if self.current.runs.is_empty() {
+                        self.push_run(" ");
                     }
+                    self.break_block();
                 }
+                self.push_run(line);
+            }
+            return;
+        }
+
+        let mut collapsed = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c.is_whitespace() && c != '\u{a0}' {
+                self.pending_space = true;
             } else {
-                if c == ']' {
-                    if let Some(&']') = chars.peek() {
-                        chars.next();
-                        skipping_brackets = false;
+                if self.pending_space {
+                    match self.current.runs.last_mut() {
+                        // The space keeps the style of the text before it, so
+                        // underlines and highlights don't start early.
+                        Some(last) if collapsed.is_empty() && last.ruby.is_empty() => {
+                            let mut text = last.text.to_string();
+                            text.push(' ');
+                            last.text = text.into();
+                        }
+                        Some(_) => collapsed.push(' '),
+                        None if !collapsed.is_empty() => collapsed.push(' '),
+                        None => {}
                     }
                 }
-                continue;
+                self.pending_space = false;
+                collapsed.push(c);
             }
         }
+        self.push_run(&collapsed);
+    }
 
-        if c.is_whitespace() {
-            if !word_buffer.is_empty() {
-                let word_len = word_buffer.chars().count();
-                let space_needed = if first_word_in_page { 0 } else { 1 };
+    /// Adds the ruby base text read so far, with its reading above it.
+    fn push_ruby(&mut self) {
+        let Some(ruby) = &mut self.ruby else {
+            return;
+        };
+        let base = collapse_whitespace(&std::mem::take(&mut ruby.base));
+        let reading = collapse_whitespace(&std::mem::take(&mut ruby.reading));
+        let ruby = self.ruby.take();
 
-                if current_len + word_len + space_needed > chars_per_page
-                    && !current_page.is_empty()
-                {
-                    pages.push(current_page.as_str().into());
-                    current_page.clear();
-                    current_len = 0;
-                    first_word_in_page = true;
+        if reading.is_empty() {
+            self.push_text(&base);
+        } else if !base.is_empty() {
+            if std::mem::take(&mut self.pending_space) && !self.current.runs.is_empty() {
+                self.current.runs.push(TextRun {
+                    text: " ".into(),
+                    ..self.style()
+                });
+            }
+            self.current.runs.push(TextRun {
+                text: base.into(),
+                ruby: reading.into(),
+                ..self.style()
+            });
+        }
+        self.ruby = ruby;
+    }
+
+    fn open_tag(&mut self, name: &str, attrs: &str) {
+        if BLOCK_TAGS.contains(&name) {
+            self.break_block();
+        }
+
+        match (name, &mut self.ruby) {
+            ("ruby", None) => self.ruby = Some(Ruby::default()),
+            ("rt", Some(ruby)) => {
+                if ruby.in_reading {
+                    self.push_ruby();
+                }
+                if let Some(ruby) = &mut self.ruby {
+                    ruby.in_reading = true;
+                }
+            }
+            ("rp", Some(ruby)) => ruby.in_parenthesis += 1,
+            _ => {}
+        }
+
+        let mut style = self.style();
+        match name {
+            "b" | "strong" => style.bold = true,
+            "i" | "em" | "cite" | "var" | "dfn" => style.italic = true,
+            "u" | "ins" | "a" => style.underline = true,
+            "code" | "kbd" | "samp" | "tt" | "pre" => style.monospace = true,
+            "mark" => style.highlight = true,
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                style.heading = true;
+                style.bold = true;
+            }
+            "th" => style.bold = true,
+            _ => {}
+        }
+        apply_attributes(&mut style, name, attrs);
+
+        match name {
+            "ul" => self.lists.push(None),
+            "ol" => self.lists.push(Some(1)),
+            "pre" => self.preformatted += 1,
+            "li" => {
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}.", *number - 1)
+                    }
+                    _ => "•".to_string(),
+                };
+                self.current.marker = Some(marker);
+            }
+            "td" | "th" if !self.current.runs.is_empty() => {
+                self.current.runs.push(TextRun {
+                    text: " │ ".into(),
+                    ..Default::default()
+                });
+                self.pending_space = false;
+            }
+            "br" => {
+                // A `<br>` on an empty line leaves a blank one.
+                if self.current.runs.is_empty() && !self.blocks.is_empty() {
+                    self.current.runs.push(TextRun::default());
+                }
+                self.break_block();
+            }
+            "hr" => {
+                self.break_block();
+                self.blocks.push(RichBlock {
+                    rule: true,
+                    ..Default::default()
+                });
+            }
+            _ => {}
+        }
+        self.current.indent = self.lists.len();
+        self.current.preformatted = self.preformatted > 0;
+
+        self.open.push((name.to_string(), style));
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        let Some(index) = self.open.iter().rposition(|(open, _)| open == name) else {
+            return;
+        };
+        self.open.truncate(index);
+
+        match name {
+            "rt" => {
+                self.push_ruby();
+                if let Some(ruby) = &mut self.ruby {
+                    ruby.in_reading = false;
                 }
+            }
+            "rp" => {
+                if let Some(ruby) = &mut self.ruby {
+                    ruby.in_parenthesis = ruby.in_parenthesis.saturating_sub(1);
+                }
+            }
+            "ruby" => {
+                self.push_ruby();
+                self.ruby = None;
+            }
+            _ => {}
+        }
 
-                if !first_word_in_page {
-                    current_page.push(' ');
-                    current_len += 1;
+        if BLOCK_TAGS.contains(&name) {
+            match name {
+                "ul" | "ol" => {
+                    self.lists.pop();
                 }
-                current_page.push_str(&word_buffer);
-                current_len += word_len;
-                first_word_in_page = false;
-                word_buffer.clear();
+                "pre" => self.preformatted = self.preformatted.saturating_sub(1),
+                _ => {}
             }
-        } else {
-            word_buffer.push(c);
+            self.break_block();
         }
     }
 
-    if !word_buffer.is_empty() {
-        let word_len = word_buffer.chars().count();
-        let space_needed = if first_word_in_page { 0 } else { 1 };
+    fn finish(mut self) -> Vec<RichBlock> {
+        self.break_block();
+        self.blocks
+    }
+}
+
+fn same_style(a: &TextRun, b: &TextRun) -> bool {
+    a.bold == b.bold
+        && a.italic == b.italic
+        && a.underline == b.underline
+        && a.monospace == b.monospace
+        && a.highlight == b.highlight
+        && a.heading == b.heading
+        && a.cloze == b.cloze
+        // Each reading stays above its own base text.
+        && a.ruby.is_empty()
+        && b.ruby.is_empty()
+}
 
-        if current_len + word_len + space_needed > chars_per_page && !current_page.is_empty() {
-            pages.push(current_page.as_str().into());
-            current_page.clear();
-            first_word_in_page = true;
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn apply_attributes(style: &mut TextRun, name: &str, attrs: &str) {
+    for caps in TAG_ATTR.captures_iter(attrs) {
+        let value = caps
+            .get(2)
+            .or_else(|| caps.get(3))
+            .or_else(|| caps.get(4))
+            .map_or("", |m| m.as_str());
+
+        match caps[1].to_ascii_lowercase().as_str() {
+            "style" => apply_css(style, value),
+            "color" if name == "font" => style.highlight |= is_color(value, "black"),
+            // Anki wraps every cloze of the card's ordinal in `cloze`, other
+            // ordinals get `cloze-inactive`.
+            "class" => {
+                for class in value.split_whitespace() {
+                    match class {
+                        "cloze" => style.cloze = true,
+                        // The diff of a typed answer.
+                        "typeGood" => style.bold = true,
+                        "typeBad" => style.highlight = true,
+                        "typeMissed" => style.underline = true,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
         }
+    }
+}
+
+fn apply_css(style: &mut TextRun, css: &str) {
+    for declaration in css.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_ascii_lowercase();
 
-        if !first_word_in_page {
-            current_page.push(' ');
+        match property.trim().to_ascii_lowercase().as_str() {
+            "font-weight" => {
+                style.bold = value.starts_with("bold")
+                    || value.parse::<u32>().is_ok_and(|weight| weight >= 600)
+            }
+            "font-style" => style.italic = value == "italic" || value == "oblique",
+            "text-decoration" | "text-decoration-line" => {
+                style.underline |= value.contains("underline")
+            }
+            "font-family" => style.monospace |= value.contains("mono") || value.contains("courier"),
+            "background" | "background-color" => style.highlight |= is_color(&value, "white"),
+            "color" => style.highlight |= is_color(&value, "black"),
+            _ => {}
         }
-        current_page.push_str(&word_buffer);
     }
+}
+
+/// Whether a CSS color stands out from the default `plain` color.
+fn is_color(value: &str, plain: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase().replace(' ', "");
+    let defaults: &[&str] = match plain {
+        "white" => &["white", "#fff", "#ffffff", "rgb(255,255,255)"],
+        _ => &["black", "#000", "#000000", "rgb(0,0,0)"],
+    };
 
-    if !current_page.is_empty() {
-        pages.push(current_page.as_str().into());
+    !value.is_empty()
+        && !defaults.contains(&value.as_str())
+        && !matches!(
+            value.as_str(),
+            "transparent" | "inherit" | "initial" | "unset" | "none" | "currentcolor"
+        )
+}
+
+/// Wraps blocks into lines that fit the page width and splits the lines
+/// into pages, measured with the fonts the UI renders them with. Lines are
+/// wrapped in logical order and then reordered for display, see
+/// `bidi::reorder_line`.
+pub fn paginate_blocks(blocks: &[RichBlock], layout: &PageLayout) -> Vec<Vec<TextLine>> {
+    let measure = TextMeasure::new(layout);
+    let height = measure.physical(layout.height);
+
+    let mut pages = Vec::new();
+    let mut page = Vec::new();
+    let mut used = 0.0;
+
+    for block in blocks {
+        for (line, line_height) in wrap_block(block, layout, &measure) {
+            if used + line_height > height && !page.is_empty() {
+                pages.push(std::mem::take(&mut page));
+                used = 0.0;
+            }
+            // A page never starts with a blank line.
+            if page.is_empty() && !line.rule && line.runs.row_count() == 0 {
+                continue;
+            }
+            used += line_height;
+            page.push(line);
+        }
     }
 
-    if pages.is_empty() {
-        pages.push(slint::SharedString::new());
+    if !page.is_empty() {
+        pages.push(page);
     }
 
     pages
 }
+
+fn wrap_block(
+    block: &RichBlock,
+    layout: &PageLayout,
+    measure: &TextMeasure,
+) -> Vec<(TextLine, f32)> {
+    let centered = !block.preformatted && block.marker.is_none() && block.indent == 0;
+    let rtl = crate::bidi::is_rtl_paragraph(
+        &block
+            .runs
+            .iter()
+            .map(|run| run.text.as_str())
+            .collect::<String>(),
+    );
+    let blank_height = measure.line_height(&TextRun::default());
+    let finish_line = |mut runs: Vec<TextRun>| {
+        if !block.preformatted
+            && let Some(last) = runs.last_mut()
+        {
+            last.text = last.text.trim_end().into();
+        }
+        let last = runs.len().saturating_sub(1);
+        for (i, run) in runs.iter_mut().enumerate() {
+            if run.text.contains(SOFT_HYPHEN) {
+                let mut text = run.text.replace(SOFT_HYPHEN, "");
+                if i == last && run.text.ends_with(SOFT_HYPHEN) {
+                    text.push('-');
+                }
+                run.text = text.into();
+            }
+        }
+        runs.retain(|run| !run.text.is_empty());
+        let runs = crate::bidi::reorder_line(runs, rtl);
+        let ruby = runs.iter().any(|run| !run.ruby.is_empty());
+        let mut height = runs
+            .iter()
+            .map(|run| measure.line_height(run))
+            .fold(blank_height, f32::max);
+        if ruby {
+            height += measure.ruby_height();
+        }
+        (
+            TextLine {
+                runs: Rc::new(slint::VecModel::from(runs)).into(),
+                indent: block.indent as i32,
+                centered,
+                rule: false,
+                ruby,
+                rtl,
+            },
+            height,
+        )
+    };
+
+    if block.rule {
+        return vec![(
+            TextLine {
+                rule: true,
+                ..Default::default()
+            },
+            blank_height,
+        )];
+    }
+
+    // Soft hyphens take no space unless a line ends at them.
+    let text_width = |run: &TextRun, text: &str| measure.width(run, &text.replace(SOFT_HYPHEN, ""));
+    let indent = measure.physical(block.indent as f32 * INDENT_EM * layout.font_size);
+    let available = (measure.physical(layout.width) - indent).max(1.0);
+
+    let mut lines = Vec::new();
+    let mut line: Vec<TextRun> = Vec::new();
+    let mut width = 0.0;
+
+    if let Some(marker) = &block.marker {
+        let marker = TextRun {
+            text: format!("{} ", marker).into(),
+            ..Default::default()
+        };
+        width += measure.width(&marker, &marker.text);
+        line.push(marker);
+    }
+
+    for segment in segments(&block.runs) {
+        let Some(&(last_run, last_text)) = segment.parts.last() else {
+            continue;
+        };
+        let mut visible: f32 = segment.parts[..segment.parts.len() - 1]
+            .iter()
+            .map(|(run, text)| text_width(run, text))
+            .sum();
+        visible += text_width(last_run, last_text.trim_end());
+        if last_text.trim_end().ends_with(SOFT_HYPHEN) {
+            visible += measure.width(last_run, "-");
+        }
+
+        if width > 0.0 && width + visible > available {
+            lines.push(finish_line(std::mem::take(&mut line)));
+            width = 0.0;
+        }
+
+        for &(run, text) in &segment.parts {
+            let mut text = if width == 0.0 && !block.preformatted {
+                text.trim_start()
+            } else {
+                text
+            };
+
+            // Longer than a whole line, split it where it overflows.
+            while width + text_width(run, text.trim_end()) > available {
+                let room = available - width - measure.width(run, "-");
+                let mut split = fitting_prefix(text, room, |head| text_width(run, head));
+                if split == 0 {
+                    if width > 0.0 {
+                        lines.push(finish_line(std::mem::take(&mut line)));
+                        width = 0.0;
+                        continue;
+                    }
+                    split = text.graphemes(true).next().map_or(text.len(), str::len);
+                }
+
+                let (head, tail) = text.split_at(split);
+                push_piece(&mut line, run, head);
+                if head.chars().last().is_some_and(hyphenates)
+                    && tail.chars().next().is_some_and(hyphenates)
+                {
+                    push_piece(&mut line, run, "-");
+                }
+                lines.push(finish_line(std::mem::take(&mut line)));
+                width = 0.0;
+                text = if block.preformatted {
+                    tail
+                } else {
+                    tail.trim_start()
+                };
+            }
+
+            width += text_width(run, text);
+            push_piece(&mut line, run, text);
+        }
+
+        if segment.mandatory {
+            lines.push(finish_line(std::mem::take(&mut line)));
+            width = 0.0;
+        }
+    }
+
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(finish_line(line));
+    }
+
+    lines
+}
+
+/// Text between two line break opportunities, which can span several runs.
+struct Segment<'a> {
+    parts: Vec<(&'a TextRun, &'a str)>,
+    /// The line has to end after this segment.
+    mandatory: bool,
+}
+
+/// Splits the text of a block at the line break opportunities of UAX #14,
+/// so Chinese and Japanese break between characters and words can end in a
+/// soft hyphen. Thai, Lao, Khmer and Burmese need a dictionary to find word
+/// boundaries, their words are only split where they overflow. Text with a
+/// reading is never broken.
+fn segments(runs: &[TextRun]) -> Vec<Segment<'_>> {
+    let text: String = runs.iter().map(|run| run.text.as_str()).collect();
+    let mut bounds = Vec::with_capacity(runs.len());
+    let mut offset = 0;
+    for run in runs {
+        bounds.push((run, offset, offset + run.text.len()));
+        offset += run.text.len();
+    }
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+
+    for (end, opportunity) in unicode_linebreak::linebreaks(&text) {
+        if bounds.iter().any(|&(run, run_start, run_end)| {
+            !run.ruby.is_empty() && run_start < end && end < run_end
+        }) {
+            continue;
+        }
+        let parts = bounds
+            .iter()
+            .filter(|&&(_, run_start, run_end)| run_start < end && start < run_end)
+            .map(|&(run, run_start, run_end)| {
+                let from = start.max(run_start) - run_start;
+                let to = end.min(run_end) - run_start;
+                (run, &run.text[from..to])
+            })
+            .collect();
+        segments.push(Segment {
+            parts,
+            mandatory: opportunity == BreakOpportunity::Mandatory && end < text.len(),
+        });
+        start = end;
+    }
+
+    segments
+}
+
+/// Byte length of the most whole graphemes at the start of `text` that are
+/// no wider than `available`.
+fn fitting_prefix(text: &str, available: f32, width: impl Fn(&str) -> f32) -> usize {
+    let ends: Vec<usize> = text
+        .grapheme_indices(true)
+        .map(|(i, grapheme)| i + grapheme.len())
+        .collect();
+    let fits = ends.partition_point(|&end| width(&text[..end]) <= available);
+    fits.checked_sub(1).map_or(0, |i| ends[i])
+}
+
+/// Words split across lines get a hyphen in the scripts that use them.
+fn hyphenates(c: char) -> bool {
+    // Latin, Greek, Cyrillic and Armenian.
+    c.is_alphabetic() && c < '\u{590}'
+}
+
+fn push_piece(line: &mut Vec<TextRun>, run: &TextRun, piece: &str) {
+    if piece.is_empty() {
+        return;
+    }
+    if let Some(last) = line.last_mut()
+        && same_style(last, run)
+    {
+        let mut merged = last.text.to_string();
+        merged.push_str(piece);
+        last.text = merged.into();
+        return;
+    }
+    line.push(TextRun {
+        text: piece.into(),
+        ..run.clone()
+    });
+}
+
+static IMG_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<img\b[^>]*>").unwrap());
+
+static TAG_ATTR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)\b([a-z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap()
+});
+
+/// Attributes of an `<img>` tag, with HTML entities decoded.
+#[derive(Default)]
+pub struct ImageTag {
+    pub src: String,
+    pub alt: String,
+    pub class: String,
+}
+
+pub enum HtmlSegment<'a> {
+    Html(&'a str),
+    Image(ImageTag),
+}
+
+/// Splits card HTML at its `<img>` tags, so images can get pages of their own
+/// between the surrounding text.
+pub fn split_images(html: &str) -> Vec<HtmlSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut last = 0;
+
+    for tag in IMG_TAG.find_iter(html) {
+        if tag.start() > last {
+            segments.push(HtmlSegment::Html(&html[last..tag.start()]));
+        }
+        segments.push(HtmlSegment::Image(parse_image_tag(tag.as_str())));
+        last = tag.end();
+    }
+
+    if last < html.len() {
+        segments.push(HtmlSegment::Html(&html[last..]));
+    }
+
+    segments
+}
+
+fn parse_image_tag(tag: &str) -> ImageTag {
+    let mut image = ImageTag::default();
+
+    for caps in TAG_ATTR.captures_iter(tag) {
+        let value = caps
+            .get(2)
+            .or_else(|| caps.get(3))
+            .or_else(|| caps.get(4))
+            .map_or("", |m| m.as_str());
+        let value = anki::text::decode_entities(value).into_owned();
+
+        match caps[1].to_ascii_lowercase().as_str() {
+            "src" => image.src = value,
+            "alt" => image.alt = value,
+            "class" => image.class = value,
+            _ => {}
+        }
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(block: &RichBlock) -> Vec<&str> {
+        block.runs.iter().map(|run| run.text.as_str()).collect()
+    }
+
+    fn layout(width: f32, height: f32) -> PageLayout {
+        PageLayout {
+            width,
+            height,
+            font_size: 20.0,
+            heading_font_size: 30.0,
+            ruby_font_size: 10.0,
+            scale_factor: 1.0,
+            ..Default::default()
+        }
+    }
+
+    fn width(text: &str) -> f32 {
+        TextMeasure::new(&layout(0.0, 0.0)).width(&TextRun::default(), text)
+    }
+
+    /// The text of each line, across pages.
+    fn lines(html: &str, layout: &PageLayout) -> Vec<String> {
+        paginate_blocks(&html_to_blocks(html), layout)
+            .iter()
+            .flatten()
+            .map(|line| line.runs.iter().map(|run| run.text.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn reads_ruby() {
+        let blocks = html_to_blocks("<ruby>漢字<rp>(</rp><rt>かんじ</rt><rp>)</rp></ruby>です");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(texts(&blocks[0]), ["漢字", "です"]);
+        assert_eq!(blocks[0].runs[0].ruby, "かんじ");
+        assert!(blocks[0].runs[1].ruby.is_empty());
+    }
+
+    #[test]
+    fn numbers_nested_lists() {
+        let blocks = html_to_blocks("<ul><li>a</li><li>b<ol><li>c</li><li>d</li></ol></li></ul>");
+        let items: Vec<_> = blocks
+            .iter()
+            .map(|block| (texts(block), block.marker.as_deref(), block.indent))
+            .collect();
+        assert_eq!(
+            items,
+            [
+                (vec!["a"], Some("•"), 1),
+                (vec!["b"], Some("•"), 1),
+                (vec!["c"], Some("1."), 2),
+                (vec!["d"], Some("2."), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn breaks_lines_and_rules() {
+        let blocks = html_to_blocks("a<br>b<br><br>c<hr>d");
+        let items: Vec<_> = blocks
+            .iter()
+            .map(|block| (texts(block), block.rule))
+            .collect();
+        assert_eq!(
+            items,
+            [
+                (vec!["a"], false),
+                (vec!["b"], false),
+                // The second `<br>` leaves a blank line.
+                (vec![""], false),
+                (vec!["c"], false),
+                (vec![], true),
+                (vec!["d"], false),
+            ]
+        );
+    }
+
+    #[test]
+    fn breaks_words_at_soft_hyphens() {
+        let layout = layout(width("over-") + 1.0, 1000.0);
+        assert_eq!(lines("over&shy;lay", &layout), ["over-", "lay"]);
+        assert_eq!(
+            lines("over&shy;lay", &self::layout(1000.0, 1000.0)),
+            ["overlay"]
+        );
+    }
+
+    #[test]
+    fn splits_words_longer_than_a_line() {
+        let word = "abcdefghijklmnop";
+        let lines = lines(word, &layout(width("abcde"), 1000.0));
+        assert!(lines.len() > 1);
+        let (last, split) = lines.split_last().unwrap();
+        assert!(split.iter().all(|line| line.ends_with('-')));
+        let joined: String = split
+            .iter()
+            .map(|line| line.trim_end_matches('-'))
+            .chain([last.as_str()])
+            .collect();
+        assert_eq!(joined, word);
+    }
+
+    #[test]
+    fn breaks_at_line_break_opportunities() {
+        let cases: &[(&str, &[&str])] = &[
+            ("hello world", &["hello ", "world"]),
+            ("a-b c", &["a-", "b ", "c"]),
+            ("word\u{ad}break", &["word\u{ad}", "break"]),
+            // Chinese and Japanese break between characters, but not before
+            // closing punctuation.
+            ("日本語です。", &["日", "本", "語", "で", "す。"]),
+            // Thai words are only split where they overflow.
+            ("ภาษาไทย", &["ภาษาไทย"]),
+        ];
+        for &(text, expected) in cases {
+            let runs = [TextRun {
+                text: text.into(),
+                ..Default::default()
+            }];
+            let found: Vec<String> = segments(&runs)
+                .iter()
+                .map(|segment| segment.parts.iter().map(|&(_, text)| text).collect())
+                .collect();
+            assert_eq!(found, expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn never_breaks_ruby() {
+        let runs = [
+            TextRun {
+                text: "漢字".into(),
+                ruby: "かんじ".into(),
+                ..Default::default()
+            },
+            TextRun {
+                text: "で".into(),
+                bold: true,
+                ..Default::default()
+            },
+        ];
+        let found: Vec<Vec<&str>> = segments(&runs)
+            .iter()
+            .map(|segment| segment.parts.iter().map(|&(_, text)| text).collect())
+            .collect();
+        assert_eq!(found, [vec!["漢字"], vec!["で"]]);
+    }
+
+    #[test]
+    fn splits_lines_into_pages() {
+        let line_height = TextMeasure::new(&layout(0.0, 0.0)).line_height(&TextRun::default());
+        let blocks = html_to_blocks("<p>a</p><p>b</p><br><p>c</p><p>d</p><p>e</p>");
+        let pages = paginate_blocks(&blocks, &layout(1000.0, line_height * 2.5));
+        let pages: Vec<Vec<String>> = pages
+            .iter()
+            .map(|page| {
+                page.iter()
+                    .map(|line| line.runs.iter().map(|run| run.text.to_string()).collect())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(pages, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    }
+
+    #[test]
+    fn splits_images_from_text() {
+        let segments = split_images(r#"<img src="map&amp;key.png" alt='Map' class=occluded>"#);
+        let [HtmlSegment::Image(image)] = segments.as_slice() else {
+            panic!("an image-only card has no text");
+        };
+        assert_eq!(image.src, "map&key.png");
+        assert_eq!(image.alt, "Map");
+        assert_eq!(image.class, "occluded");
+
+        let segments = split_images(r#"a<IMG SRC="b.png">c"#);
+        assert!(matches!(
+            segments.as_slice(),
+            [
+                HtmlSegment::Html("a"),
+                HtmlSegment::Image(ImageTag { src, .. }),
+                HtmlSegment::Html("c"),
+            ] if src == "b.png"
+        ));
+    }
+}
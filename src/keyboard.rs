@@ -0,0 +1,61 @@
+use std::ffi::{CStr, c_char};
+use std::sync::Mutex;
+
+use common::{KeyboardField, MainWindow};
+
+/// Longest text the on-screen keyboard accepts, including the terminating nul.
+pub const BUFFER_SIZE: usize = 1024;
+
+struct Pending {
+    buffer: Box<[u8; BUFFER_SIZE]>,
+    field: KeyboardField,
+    ui: slint::Weak<MainWindow>,
+}
+
+/// The keyboard callback gets no user data, so the open request is kept here.
+static PENDING: Mutex<Option<Pending>> = Mutex::new(None);
+
+/// Prepares the buffer handed to `OpenKeyboard`, pre-filled with `text`. It
+/// stays alive until [`handler`] runs, which writes it back to `field`.
+pub fn buffer(text: &str, field: KeyboardField, ui: slint::Weak<MainWindow>) -> *mut c_char {
+    let mut buffer = Box::new([0u8; BUFFER_SIZE]);
+    let len = text.len().min(BUFFER_SIZE - 1);
+    let len = (0..=len)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0);
+    buffer[..len].copy_from_slice(&text.as_bytes()[..len]);
+
+    let mut pending = PENDING.lock().unwrap();
+    let pending = pending.insert(Pending { buffer, field, ui });
+    pending.buffer.as_mut_ptr() as *mut c_char
+}
+
+/// The `OpenKeyboard` flags for `field`, passwords are masked.
+pub fn flags(field: KeyboardField) -> i32 {
+    match field {
+        KeyboardField::Password => inkview::bindings::KBD_PASSWORD as i32,
+        KeyboardField::TypedAnswer | KeyboardField::Username => 0,
+    }
+}
+
+/// Called by inkview on its own thread once the keyboard is closed, with a
+/// null pointer if it was cancelled.
+pub unsafe extern "C" fn handler(text: *mut c_char) {
+    let Some(pending) = PENDING.lock().unwrap().take() else {
+        return;
+    };
+    if text.is_null() {
+        return;
+    }
+
+    let typed = CStr::from_bytes_until_nul(&pending.buffer[..])
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let field = pending.field;
+    let _ = pending.ui.upgrade_in_event_loop(move |ui| match field {
+        KeyboardField::TypedAnswer => ui.set_typed_answer(typed.into()),
+        KeyboardField::Username => ui.set_ankiweb_username(typed.into()),
+        KeyboardField::Password => ui.set_ankiweb_password(typed.into()),
+    });
+}
@@ -1,3 +1,5 @@
+mod keyboard;
+
 use common::*;
 
 use std::rc::Rc;
@@ -28,45 +30,344 @@ fn main() {
                     Config::default()
                 });
 
-            let session = init_session(&config);
-            let translations = init_translations(&session);
-
             let ui = Rc::new(MainWindow::new().unwrap());
 
+            let started = init_session(&config).and_then(|session| {
+                let translations = init_translations(&session)?;
+                Ok((session, translations))
+            });
+            let (session, translations) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    ui.set_fatal_error(true);
+                    ui.set_error_message(e.to_string().into());
+                    ui.run().unwrap();
+                    return;
+                }
+            };
+
             let session_for_deck_tree = session.clone();
             let ui_weak_for_deck_tree = ui.as_weak();
             ui.on_update_deck_tree(move || {
-                let deck_tree = update_deck_tree(&session_for_deck_tree);
-                if let Some(ui) = ui_weak_for_deck_tree.upgrade() {
-                    ui.set_deck_tree(deck_tree);
+                let Some(ui) = ui_weak_for_deck_tree.upgrade() else {
+                    return;
+                };
+                match update_deck_tree(&session_for_deck_tree) {
+                    Ok(deck_tree) => ui.set_deck_tree(deck_tree),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
                 }
             });
 
             let session_for_rate = session.clone();
             let ui_weak_for_rate = ui.as_weak();
 
-            ui.on_rate(move |rating, deck, chars_per_page| {
-                let next = rate_card(&session_for_rate, rating, deck, chars_per_page);
-                if let Some(ui) = ui_weak_for_rate.upgrade() {
-                    ui.set_current_card(next);
+            ui.on_rate(move |rating, deck, layout| {
+                let Some(ui) = ui_weak_for_rate.upgrade() else {
+                    return;
+                };
+                match rate_card(&session_for_rate, rating, deck, layout) {
+                    Ok(next) => ui.set_current_card(next),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_undo = session.clone();
+            let ui_weak_for_undo = ui.as_weak();
+
+            ui.on_undo(move |deck, layout| {
+                let Some(ui) = ui_weak_for_undo.upgrade() else {
+                    return;
+                };
+                match undo(&session_for_undo, deck, layout) {
+                    Ok(card) => ui.set_current_card(card),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_card_action = session.clone();
+            let ui_weak_for_card_action = ui.as_weak();
+
+            ui.on_card_action(move |action, deck, layout| {
+                let Some(ui) = ui_weak_for_card_action.upgrade() else {
+                    return;
+                };
+                match card_action(&session_for_card_action, action, deck, layout) {
+                    Ok(card) => ui.set_current_card(card),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_flag = session.clone();
+            let ui_weak_for_flag = ui.as_weak();
+
+            ui.on_set_flag(move |flag| {
+                let Some(ui) = ui_weak_for_flag.upgrade() else {
+                    return;
+                };
+                match set_flag(&session_for_flag, flag) {
+                    Ok(()) => {
+                        let mut card = ui.get_current_card();
+                        card.flag = flag;
+                        ui.set_current_card(card);
+                    }
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_marked = session.clone();
+            let ui_weak_for_marked = ui.as_weak();
+
+            ui.on_set_marked(move |marked| {
+                let Some(ui) = ui_weak_for_marked.upgrade() else {
+                    return;
+                };
+                match set_marked(&session_for_marked, marked) {
+                    Ok(()) => {
+                        let mut card = ui.get_current_card();
+                        card.marked = marked;
+                        ui.set_current_card(card);
+                    }
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_stats = session.clone();
+            let ui_weak_for_stats = ui.as_weak();
+
+            ui.on_load_stats(move |deck, whole_collection| {
+                let Some(ui) = ui_weak_for_stats.upgrade() else {
+                    return;
+                };
+                match deck_stats(&session_for_stats, deck, whole_collection) {
+                    Ok(stats) => ui.set_stats(stats),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_options = session.clone();
+            let ui_weak_for_options = ui.as_weak();
+
+            ui.on_load_deck_options(move |deck| {
+                let Some(ui) = ui_weak_for_options.upgrade() else {
+                    return;
+                };
+                match deck_options(&session_for_options, deck) {
+                    Ok(options) => ui.set_deck_options(options),
+                    Err(e) => {
+                        ui.set_error_message(e.to_string().into());
+                        ui.set_deck_options_open(false);
+                    }
+                }
+            });
+
+            let session_for_save_options = session.clone();
+            let ui_weak_for_save_options = ui.as_weak();
+
+            ui.on_save_deck_options(move |deck, options| {
+                let Some(ui) = ui_weak_for_save_options.upgrade() else {
+                    return;
+                };
+                match save_deck_options(&session_for_save_options, deck, options) {
+                    Ok(()) => {
+                        ui.set_deck_options_open(false);
+                        // The limits change the deck's counts.
+                        ui.invoke_update_deck_tree();
+                    }
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let ui_weak_for_step = ui.as_weak();
+
+            ui.on_edit_step(move |relearn, index, edit| {
+                let Some(ui) = ui_weak_for_step.upgrade() else {
+                    return;
+                };
+                let mut options = ui.get_deck_options();
+                let steps = if relearn {
+                    &mut options.relearn_steps
+                } else {
+                    &mut options.learn_steps
+                };
+                *steps = deck_options::edit_steps(steps, index.max(0) as usize, edit);
+                ui.set_deck_options(options);
+            });
+
+            let session_for_custom_study = session.clone();
+            let ui_weak_for_custom_study = ui.as_weak();
+
+            ui.on_load_custom_study(move |deck| {
+                let Some(ui) = ui_weak_for_custom_study.upgrade() else {
+                    return;
+                };
+                match custom_study_defaults(&session_for_custom_study, deck) {
+                    Ok(defaults) => ui.set_custom_study_defaults(defaults),
+                    Err(e) => {
+                        ui.set_error_message(e.to_string().into());
+                        ui.set_custom_study_open(false);
+                    }
+                }
+            });
+
+            let session_for_study = session.clone();
+            let ui_weak_for_study = ui.as_weak();
+
+            ui.on_custom_study(move |deck, mode, value| {
+                let Some(ui) = ui_weak_for_study.upgrade() else {
+                    return;
+                };
+                match custom_study(&session_for_study, deck, mode, value) {
+                    Ok(()) => {
+                        ui.set_custom_study_open(false);
+                        // Shows the new limits or the "Custom Study Session" deck.
+                        ui.invoke_update_deck_tree();
+                    }
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let session_for_filtered = session.clone();
+            let ui_weak_for_filtered = ui.as_weak();
+
+            ui.on_filtered_deck_action(move |deck, action| {
+                let Some(ui) = ui_weak_for_filtered.upgrade() else {
+                    return;
+                };
+                match filtered_deck_action(&session_for_filtered, deck, action) {
+                    Ok(()) => ui.invoke_update_deck_tree(),
+                    Err(e) => ui.set_error_message(e.to_string().into()),
                 }
             });
 
             let session_for_deck = session.clone();
             let ui_weak_for_deck = ui.as_weak();
 
-            ui.on_deck_clicked(move |deck, chars_per_page| {
-                let next = next_card(&session_for_deck, deck, chars_per_page);
-                if let Some(ui) = ui_weak_for_deck.upgrade() {
-                    ui.set_current_card(next);
+            ui.on_deck_clicked(move |deck, layout| {
+                let Some(ui) = ui_weak_for_deck.upgrade() else {
+                    return;
+                };
+                match start_session(&session_for_deck, deck, layout) {
+                    Ok(next) => ui.set_current_card(next),
+                    Err(e) => {
+                        ui.set_error_message(e.to_string().into());
+                        ui.set_learning(false);
+                    }
+                }
+            });
+
+            let session_for_check = session.clone();
+            let ui_weak_for_check = ui.as_weak();
+
+            ui.on_check_answer(move |typed, layout| {
+                let Some(ui) = ui_weak_for_check.upgrade() else {
+                    return;
+                };
+                match check_answer(&session_for_check, &typed, layout) {
+                    Ok(answer) => {
+                        let mut card = ui.get_current_card();
+                        card.answer = answer;
+                        ui.set_current_card(card);
+                    }
+                    Err(e) => ui.set_error_message(e.to_string().into()),
+                }
+            });
+
+            let ui_weak_for_keyboard = ui.as_weak();
+
+            ui.on_open_keyboard(move |field, text| {
+                let buffer = keyboard::buffer(&text, field, ui_weak_for_keyboard.clone());
+                unsafe {
+                    iv.OpenKeyboard(
+                        c"".as_ptr(),
+                        buffer,
+                        keyboard::BUFFER_SIZE as i32 - 1,
+                        keyboard::flags(field),
+                        Some(keyboard::handler),
+                    );
+                }
+            });
+
+            let session_for_sync = session.clone();
+            let config_for_sync = config.clone();
+            let ui_weak_for_sync = ui.as_weak();
+
+            ui.on_sync(move || {
+                if let Err(e) = start_sync(
+                    &session_for_sync,
+                    &config_for_sync,
+                    ui_weak_for_sync.clone(),
+                    SyncJob::Normal,
+                ) && let Some(ui) = ui_weak_for_sync.upgrade()
+                {
+                    ui.set_error_message(e.to_string().into());
+                }
+            });
+
+            let session_for_full_sync = session.clone();
+            let config_for_full_sync = config.clone();
+            let ui_weak_for_full_sync = ui.as_weak();
+
+            ui.on_full_sync(move |upload| {
+                if let Err(e) = start_sync(
+                    &session_for_full_sync,
+                    &config_for_full_sync,
+                    ui_weak_for_full_sync.clone(),
+                    SyncJob::Full { upload },
+                ) && let Some(ui) = ui_weak_for_full_sync.upgrade()
+                {
+                    ui.set_error_message(e.to_string().into());
+                }
+            });
+
+            let session_for_login = session.clone();
+            let config_for_login = config.clone();
+            let ui_weak_for_login = ui.as_weak();
+
+            ui.on_login(move |username, password| {
+                if let Err(e) = start_sync(
+                    &session_for_login,
+                    &config_for_login,
+                    ui_weak_for_login.clone(),
+                    SyncJob::Login {
+                        username: username.into(),
+                        password: password.into(),
+                    },
+                ) && let Some(ui) = ui_weak_for_login.upgrade()
+                {
+                    ui.set_error_message(e.to_string().into());
                 }
             });
 
             ui.set_tr(translations);
+            ui.set_native_keyboard(true);
 
-            ui.set_deck_tree(update_deck_tree(&session));
+            ui.invoke_update_deck_tree();
+            ui.set_sync_on_exit(config.ankiweb.sync_on_exit);
+            ui.set_furigana_answer_only(config.general.furigana_answer_only);
+            ui.set_rtl(bidi::is_rtl_language(&config.general.language));
+            ui.set_ankiweb_username(config.ankiweb.username.as_str().into());
+
+            if config.ankiweb.auto_sync {
+                ui.invoke_sync();
+            }
 
             ui.run().unwrap();
+
+            if config.ankiweb.sync_on_exit {
+                wait_for_sync(&session);
+                match sync_ankiweb(&session, &config) {
+                    Ok(result) => {
+                        eprintln!("{}", result.message);
+                        if let Some(server_message) =
+                            result.server_message.filter(|m| !m.is_empty())
+                        {
+                            eprintln!("{}", server_message);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
         }
     });
 